@@ -0,0 +1,351 @@
+use std::{collections::{HashMap, HashSet}, sync::Arc};
+
+use rand::Rng;
+use rocket::{State, http::ContentType, serde::json::Json};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{
+    Server,
+    actions::send_sendable,
+    audit::{AuditAction, AuditEntry},
+    linking::rename_pairings,
+    logging::RequestSpan,
+    media::MediaConfig,
+    media_serving::remove_pfp_files,
+    search::SearchIndex,
+    sendables::banner,
+    sessions::{end_session, ClientInfo},
+    user::{UserIdentifier, UserProfile},
+    user_db::{DBEntry, DBEntryType, UserDB},
+    validation::{validate_username, username_taken, FieldErrors, ValidationConfig},
+};
+
+// what a deleted user's messages are attributed to in everyone else's history
+pub const DELETED_USERNAME: &str = "[deleted]";
+
+#[derive(Deserialize)]
+pub struct ChangePassword {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangeUsername {
+    pub new_username: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccount {
+    pub password: String,
+}
+
+pub fn new_user_id(users: &HashMap<UserIdentifier, UserProfile>) -> u32 {
+    let taken: HashSet<u32> = users.values().map(|profile| profile.id).collect();
+    let mut rng = rand::thread_rng();
+    let mut id = rng.gen::<u32>();
+    while id == 0 || taken.contains(&id) {
+        id = rng.gen::<u32>();
+    }
+    id
+}
+
+// profiles saved before user ids existed get one, and their messages get tagged with it
+pub fn fill_missing_user_ids(users: &mut HashMap<UserIdentifier, UserProfile>, user_db: &mut HashMap<UserIdentifier, UserDB>) {
+    let missing: Vec<UserIdentifier> = users.iter().filter(|(_, profile)| profile.id == 0).map(|(uid, _)| uid.clone()).collect();
+    for uid in missing {
+        let id = new_user_id(users);
+        users.get_mut(&uid).unwrap().id = id;
+    }
+    for udb in user_db.values_mut() {
+        for entries in udb.messages.map.values_mut() {
+            for entry in entries.map.values_mut() {
+                if let Some(message) = entry.message.as_mut().filter(|message| message.from_id == 0) {
+                    if let Some(profile) = users.get(&message.from_user) {
+                        message.from_id = profile.id;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn check_password(server: &Server, uid: &UserIdentifier, password: &str) -> bool {
+    server.passwords.lock().get(uid).map(|stored| stored == password).unwrap_or(false)
+}
+
+// re-keys every store from `old` to `new`. the user id on the profile and on messages stays the same,
+// which is what clients should use to recognise the same person across renames. returns false without
+// changing anything if someone else took `new` first.
+// every lock keyed by username is held, in field order, while the maps are re-keyed, so requests with
+// this user's token wait instead of resolving to the old name. rewriting history goes through every
+// user's db, so it happens after they're released, with `old` reserved in `renaming` until it's done
+// so a signup can't claim it and have its messages or reactions moved onto this account
+pub fn migrate_username(server: &Server, old: &UserIdentifier, new: &UserIdentifier) -> bool {
+    fn rekey<T>(map: &mut HashMap<UserIdentifier, T>, old: &UserIdentifier, new: &UserIdentifier) {
+        if let Some(value) = map.remove(old) {
+            map.insert(new.clone(), value);
+        }
+    }
+    {
+        let mut pairings = server.pairings.lock();
+        let mut tokens = server.tokens.lock();
+        let mut passwords = server.passwords.lock();
+        let mut keys = server.keys.lock();
+        let mut users = server.users.lock();
+        let mut contacts = server.contacts.lock();
+        // changing only the case of your own name is allowed
+        if username_taken(&new.username, &users, Some(old)) || server.renaming.lock().contains(&new.username.to_lowercase()) {
+            return false;
+        }
+        rekey(&mut users, old, new);
+        if let Some(profile) = users.get_mut(new) {
+            profile.username = new.username.clone();
+        }
+        rekey(&mut passwords, old, new);
+        rekey(&mut keys, old, new);
+        rekey(&mut contacts, old, new);
+        for uid in tokens.values_mut() {
+            if uid == old {
+                *uid = new.clone();
+            }
+        }
+        rename_pairings(&mut pairings, old, new);
+        for (_, chat) in server.chats.entries() {
+            let mut chat = chat.lock();
+            for user in chat.users.iter_mut() {
+                if user == old {
+                    *user = new.clone();
+                }
+            }
+            if &chat.admin == old {
+                chat.admin = new.clone();
+            }
+        }
+        server.user_db.rekey(old, new.clone());
+        server.search_indexes.rekey(old, new.clone());
+        for info in server.media.lock().blobs.values_mut() {
+            if info.uploader == old.username {
+                info.uploader = new.username.clone();
+            }
+        }
+        rekey(&mut server.event_stream_senders.lock(), old, new);
+        server.renaming.lock().insert(old.username.to_lowercase());
+    }
+    rename_in_history(server, old, new);
+    server.renaming.lock().remove(&old.username.to_lowercase());
+    true
+}
+
+// rewrites authorship and reactions in every UserDB, returns whose history changed
+fn rename_in_history(server: &Server, old: &UserIdentifier, new: &UserIdentifier) -> Vec<UserIdentifier> {
+    let mut changed = Vec::new();
+    for (uid, udb) in server.user_db.entries() {
+        let mut udb = udb.lock();
+        let mut touched = false;
+        for entries in udb.messages.map.values_mut() {
+            for entry in entries.map.values_mut() {
+                if entry.entry_type != DBEntryType::Message {
+                    continue;
+                }
+                let message = entry.message.as_mut().unwrap();
+                if &message.from_user == old {
+                    message.from_user = new.clone();
+                    if new.username == DELETED_USERNAME {
+                        message.from_id = 0;
+                    }
+                    touched = true;
+                }
+                if let Some(emoji) = message.reactions.remove(&old.username) {
+                    if new.username != DELETED_USERNAME {
+                        message.reactions.insert(new.username.clone(), emoji);
+                    }
+                    touched = true;
+                }
+            }
+        }
+        if touched {
+            changed.push(uid.clone());
+        }
+    }
+    // the search index keeps the sender's username, rebuild it for anyone whose history changed
+    let chats = server.chats.snapshot();
+    for uid in &changed {
+        if let Some(index) = server.user_db.with(uid, |udb| SearchIndex::build(udb, &chats)) {
+            server.search_indexes.insert(uid.clone(), index);
+        }
+    }
+    changed
+}
+
+pub fn post_banner(server: &Server, text: String, chatid: u32, users: &Vec<UserIdentifier>) {
+    let banner_id = rand::thread_rng().gen::<u32>();
+    let sendable = banner(text, chatid, banner_id);
+    send_sendable(sendable.clone(), users, server);
+    for user in users {
+        server.user_db.with(user, |udb| udb.add_to_chat(chatid, banner_id, DBEntry::sendable(sendable.clone())));
+    }
+}
+
+#[post("/change-password/<token>", data = "<change>")]
+pub fn change_password(token: u32, change: Json<ChangePassword>, client: ClientInfo, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let uid = match server.token_user(token) {
+        Some(uid) => uid,
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    if !check_password(server, &uid, &change.old_password) {
+        return (ContentType::JSON, "{\"server\":\"incorrect password\"}".into());
+    }
+    if change.new_password.is_empty() {
+        return (ContentType::JSON, "{\"server\":\"empty password\"}".into());
+    }
+    server.passwords.lock().insert(uid.clone(), change.new_password.clone());
+    // anyone who had the old password loses their sessions, the caller stays logged in
+    let others: Vec<u32> = server
+        .tokens
+        .lock()
+        .iter()
+        .filter(|(other, other_uid)| **other != token && **other_uid == uid)
+        .map(|(other, _)| *other)
+        .collect();
+    for other in &others {
+        end_session(server, *other);
+    }
+    let user_id = server.user_id(&uid).unwrap_or(0);
+    server.audit.record(AuditEntry::new(AuditAction::PasswordChanged, user_id).user(user_id).ip(&client).detail(format!("{} other sessions revoked", others.len())));
+    (ContentType::JSON, format!("{{\"server\":\"password changed\", \"revoked\":{}}}", others.len()))
+}
+
+#[post("/change-username/<token>", data = "<change>")]
+pub fn change_username(token: u32, change: Json<ChangeUsername>, validation: &State<ValidationConfig>, client: ClientInfo, request: RequestSpan, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let _entered = request.enter();
+    let server: &Server = server_arc;
+    let uid = match server.token_user(token) {
+        Some(uid) => uid,
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    if !check_password(server, &uid, &change.password) {
+        return (ContentType::JSON, "{\"server\":\"incorrect password\"}".into());
+    }
+    let new_uid = UserIdentifier { username: change.new_username.clone() };
+    let mut errors = FieldErrors::new();
+    errors.check("new_username", validate_username(&new_uid.username, validation));
+    if !errors.is_empty() {
+        return (ContentType::JSON, errors.to_json());
+    }
+    if new_uid == uid {
+        return (ContentType::JSON, "{\"server\":\"same username\"}".into());
+    }
+    if !migrate_username(server, &uid, &new_uid) {
+        return (ContentType::JSON, "{\"server\":\"exists\"}".into());
+    }
+    let name = server.users.lock().get(&new_uid).map(|profile| profile.name.clone()).unwrap_or_default();
+    let shared_chats: Vec<(u32, Vec<UserIdentifier>)> = server
+        .chats
+        .entries()
+        .into_iter()
+        .map(|(_, chat)| chat.lock().clone())
+        .filter(|chat| chat.users.contains(&new_uid))
+        .map(|chat| (chat.id, chat.users))
+        .collect();
+    for (chatid, users) in shared_chats {
+        post_banner(server, format!("{} is now @{}", name, new_uid.username), chatid, &users);
+    }
+    info!("username changed");
+    let user_id = server.user_id(&new_uid).unwrap_or(0);
+    server.audit.record(AuditEntry::new(AuditAction::UsernameChanged, user_id).user(user_id).ip(&client).detail(format!("{} to {}", uid.username, new_uid.username)));
+    (ContentType::JSON, format!("{{\"username\":{}}}", serde_json::to_string(&new_uid.username).unwrap()))
+}
+
+#[post("/delete-account/<token>", data = "<delete>")]
+pub fn delete_account(token: u32, delete: Json<DeleteAccount>, config: &State<MediaConfig>, client: ClientInfo, request: RequestSpan, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let _entered = request.enter();
+    let server: &Server = server_arc;
+    let uid = match server.token_user(token) {
+        Some(uid) => uid,
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    // the password, every session and any pending link go first, under one lock, so nothing can log
+    // in or get a token for the account while the rest is torn down
+    {
+        let mut pairings = server.pairings.lock();
+        let mut tokens = server.tokens.lock();
+        let mut sessions = server.sessions.lock();
+        let mut passwords = server.passwords.lock();
+        if passwords.get(&uid) != Some(&delete.password) {
+            return (ContentType::JSON, "{\"server\":\"incorrect password\"}".into());
+        }
+        passwords.remove(&uid);
+        tokens.retain(|token, token_uid| {
+            let keep = token_uid != &uid;
+            if !keep {
+                sessions.remove(token);
+            }
+            keep
+        });
+        pairings.retain(|_, pairing| pairing.uid != uid);
+    }
+    // dropping the senders ends the streams
+    server.event_stream_senders.lock().remove(&uid);
+    let profile = server.users.lock().get(&uid).cloned().unwrap_or(UserProfile::dummy(uid.username.clone()));
+
+    // leave every chat, handing admin to whoever is left and dropping chats nobody is left in
+    let mut left_chats = Vec::new();
+    let mut empty_chats = Vec::new();
+    for (_, chat) in server.chats.entries() {
+        let mut chat = chat.lock();
+        if !chat.users.contains(&uid) {
+            continue;
+        }
+        chat.users.retain(|user| user != &uid);
+        match chat.users.first() {
+            Some(next_admin) => {
+                if chat.admin == uid {
+                    chat.admin = next_admin.clone();
+                }
+                left_chats.push((chat.id, chat.users.clone()));
+            }
+            None => empty_chats.push(chat.id),
+        }
+    }
+    for chatid in &empty_chats {
+        server.chats.remove(chatid);
+    }
+    server.chat_join_ids.lock().retain(|_, chatid| !empty_chats.contains(chatid));
+    for (chatid, users) in &left_chats {
+        post_banner(server, format!("{} deleted their account", profile.name), *chatid, users);
+    }
+
+    if let Some(udb) = server.user_db.remove(&uid) {
+        let udb = udb.lock();
+        let mut media = server.media.lock();
+        for entries in udb.messages.map.values() {
+            for entry in entries.map.values() {
+                if let Some(message) = &entry.message {
+                    media.release(message);
+                }
+            }
+        }
+    }
+    rename_in_history(server, &uid, &UserIdentifier { username: DELETED_USERNAME.to_string() });
+    server.users.lock().remove(&uid);
+    server.accounts.lock().remove(&profile.id);
+    server.keys.lock().remove(&uid);
+    {
+        let mut contacts = server.contacts.lock();
+        contacts.remove(&uid);
+        for saved in contacts.values_mut() {
+            saved.remove(&profile.id);
+        }
+    }
+    server.search_indexes.remove(&uid);
+    server.scheduled.lock().retain(|_, pending| pending.owner != profile.id);
+    if !profile.pfp.is_empty() {
+        remove_pfp_files(config, &profile.pfp);
+    }
+    info!("account deleted");
+    server.audit.record(AuditEntry::new(AuditAction::AccountDeleted, profile.id).user(profile.id).ip(&client).detail(&uid.username));
+    (ContentType::JSON, "{\"server\":\"deleted\"}".into())
+}
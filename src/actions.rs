@@ -1,32 +1,39 @@
-use std::sync::MutexGuard;
+use std::{collections::HashMap, time::Instant};
 
-use crate::{user::UserIdentifier, message::Message, Server, sendables::{Sendable, SendableType}, user_db::{UserDB, DBEntry, DBMap}};
+use tracing::debug;
 
-pub fn send_sendable(sendable: Sendable, users: &Vec<UserIdentifier>, server: &MutexGuard<Server>) {
+use crate::{contacts::is_blocked, expiry::expires_at, keys::DeviceKey, search::index_message, user::UserIdentifier, message::{EncryptedMessages, Message}, Server, sendables::{Sendable, SendableType, read_batch}, user_db::{UserDB, DBEntry, DBEntryType, DBMap, TimeStamped}};
+
+pub fn send_sendable(sendable: Sendable, users: &Vec<UserIdentifier>, server: &Server) {
     for user in users {
-        println!("sending to {}", user.username);
-        let mut event_stream_senders = server.event_stream_senders.lock().unwrap();
-        let senders_option = event_stream_senders.get_mut(user);
-        // let mut sent = false;
-        if senders_option.is_some() {
-            let mut new_senders = Vec::new();
-            for sender in senders_option.unwrap() {
-                match sender.send(sendable.clone()) {
-                    Ok(_) => {
-                        // sent = true;
-                        new_senders.push(sender.clone());
+        // (delivered, dropped) streams, logged after the senders are unlocked since user ids come
+        // from further up the lock order
+        let mut streams = (0, 0);
+        {
+            let mut event_stream_senders = server.event_stream_senders.lock();
+            let senders_option = event_stream_senders.get_mut(user);
+            // let mut sent = false;
+            if senders_option.is_some() {
+                let mut new_senders = Vec::new();
+                for sender in senders_option.unwrap() {
+                    match sender.sender.send(sendable.clone()) {
+                        Ok(_) => {
+                            // sent = true;
+                            new_senders.push(sender.clone());
+                            streams.0 += 1;
+                        }
+                        Err(_) => streams.1 += 1,
                     }
-                    Err(e) => println!("{e} when sending to user {}", user.username),
                 }
+                event_stream_senders.insert(user.clone(), new_senders);
             }
-            event_stream_senders.insert(user.clone(), new_senders);
-        } else {
-            println!("user {} has no senders list", user.username);
         }
+        server.metrics.record_sends(streams.0, streams.1);
+        debug!(user_id = server.user_id(user), kind = ?sendable.sendable_type, delivered = streams.0, dropped = streams.1, "event fanned out");
         // if !sent {
-        //     if !server.sendable_queue.lock().unwrap().contains_key(&user) {
+        //     if !server.sendable_queue.lock().contains_key(&user) {
         //         println!("creating sending queue for user {}", user.username);
-        //         server.sendable_queue.lock().unwrap().insert(user.clone(), Vec::new());
+        //         server.sendable_queue.lock().insert(user.clone(), Vec::new());
         //     }
         //     server
         //         .sendable_queue
@@ -39,29 +46,46 @@ pub fn send_sendable(sendable: Sendable, users: &Vec<UserIdentifier>, server: &M
     }
 }
 
-pub fn send_message(message: Message, to_user: UserIdentifier, server: MutexGuard<Server>) {
-    let mut event_stream_senders = server.event_stream_senders.lock().unwrap();
-    let senders_option = event_stream_senders.get_mut(&to_user);
-    // let mut sent = false;
-    let sendable = Sendable::new(SendableType::Message, serde_json::ser::to_string(&message).expect("couldn't serialize message"), None);
-    if senders_option.is_some() {
-        let mut new_senders = Vec::new();
-        for sender in senders_option.unwrap() {
-            match sender.send(sendable.clone()) {
-                Ok(_) => {
-                    // sent = true;
-                    new_senders.push(sender.clone());
+// send_sendable for things a user did (reactions, receipts), skipping anyone who blocked them
+pub fn send_sendable_from(sendable: Sendable, from: &UserIdentifier, users: &[UserIdentifier], server: &Server) {
+    let users: Vec<UserIdentifier> = users.iter().filter(|user| !is_blocked(server, user, from)).cloned().collect();
+    send_sendable(sendable, &users, server);
+}
+
+// returns false without storing anything when `to_user` has blocked the sender or doesn't exist
+pub fn send_message(mut message: Message, to_user: UserIdentifier, server: &Server) -> bool {
+    if is_blocked(server, &to_user, &message.from_user) {
+        return false;
+    }
+    message.expires_at = expires_at(server, message.chat);
+    let started = Instant::now();
+    let mut streams = (0, 0);
+    {
+        let mut event_stream_senders = server.event_stream_senders.lock();
+        let senders_option = event_stream_senders.get_mut(&to_user);
+        // let mut sent = false;
+        if senders_option.is_some() {
+            let mut new_senders = Vec::new();
+            for sender in senders_option.unwrap() {
+                // every device only gets its own ciphertext
+                let sendable = Sendable::new(SendableType::Message, serde_json::ser::to_string(&message.for_device(sender.device)).expect("couldn't serialize message"), None);
+                match sender.sender.send(sendable) {
+                    Ok(_) => {
+                        // sent = true;
+                        new_senders.push(sender.clone());
+                        streams.0 += 1;
+                    }
+                    Err(_) => streams.1 += 1,
                 }
-                Err(e) => {
-                    println!("{e}");
-                },
             }
+            event_stream_senders.insert(to_user.clone(), new_senders);
         }
-        event_stream_senders.insert(to_user.clone(), new_senders);
     }
+    server.metrics.record_sends(streams.0, streams.1);
+    debug!(user_id = server.user_id(&to_user), chatid = message.chat, delivered = streams.0, dropped = streams.1, "message fanned out");
     // if !sent {
-    //     if !server.message_queue.lock().unwrap().contains_key(&to_user) {
-    //         server.message_queue.lock().unwrap().insert(to_user.clone(), HashMap::new());
+    //     if !server.message_queue.lock().contains_key(&to_user) {
+    //         server.message_queue.lock().insert(to_user.clone(), HashMap::new());
     //     }
     //     server
     //     .message_queue
@@ -71,16 +95,122 @@ pub fn send_message(message: Message, to_user: UserIdentifier, server: MutexGuar
     //     .unwrap_or(&mut HashMap::new())
     //     .insert(message.id, sendable.clone());
     // }
-    let mut user_db = server.user_db.lock().unwrap();
-    if !user_db.contains_key(&to_user) {
-        user_db.insert(to_user.clone(), UserDB::new());
+    // a db is only created for someone who has a profile, checked under the users lock so a rename
+    // can't move them away in between and leave the new db orphaned under the old name. an existing
+    // db is the same shard after a rename, so writing to it is fine either way
+    let shard = match server.user_db.get(&to_user) {
+        Some(shard) => shard,
+        None => {
+            let users = server.users.lock();
+            if !users.contains_key(&to_user) {
+                return false;
+            }
+            server.user_db.get_or_insert(&to_user, UserDB::new)
+        }
+    };
+    {
+        let mut udb = shard.lock();
+        let messages = &mut udb.messages;
+        if !messages.contains_key(&message.chat) {
+            messages.insert(message.chat.clone(), DBMap::new());
+        }
+        let mut chat_entries = messages.get(&message.chat).unwrap().clone();
+        if let Some(DBEntry { message: Some(replaced), .. }) = chat_entries.get(&message.id) {
+            server.media.lock().release(replaced);
+        }
+        server.media.lock().retain(&message);
+        chat_entries.insert(message.id.clone(), DBEntry::message(message.clone()));
+        messages.update(message.chat.clone(), chat_entries);
+    }
+    index_message(&message, &to_user, server);
+    server.metrics.record_fanout(streams.0, started.elapsed());
+    true
+}
+
+// recipients that have registered devices the sender didn't encrypt for, with their full current
+// device list so the client can fetch the new keys and retry. a username keyed ciphertext covers
+// the devices that still use the key on that user's profile
+pub fn missing_devices(encrypted_messages: &EncryptedMessages, server: &Server) -> HashMap<String, Vec<DeviceKey>> {
+    let keys = server.keys.lock();
+    let users = server.users.lock();
+    let mut missing = HashMap::new();
+    for username in encrypted_messages.recipients() {
+        let uid = UserIdentifier { username: username.clone() };
+        let devices = match keys.get(&uid) {
+            Some(user_keys) if !user_keys.devices.is_empty() => &user_keys.devices,
+            _ => continue,
+        };
+        let profile_key = users.get(&uid).map(|user| user.public_key.clone()).unwrap_or_default();
+        let has_shared_copy = encrypted_messages.encrypted_messages.contains_key(&username);
+        let device_copies = encrypted_messages.device_messages.get(&username);
+        let all_covered = devices.iter().all(|device| {
+            device_copies.map(|copies| copies.contains_key(&device.device_id)).unwrap_or(false)
+                || (has_shared_copy && device.public_key == profile_key)
+        });
+        if !all_covered {
+            missing.insert(username, devices.clone());
+        }
     }
-    let udb = user_db.get_mut(&to_user).unwrap();
-    let messages = &mut udb.messages;
-    if !messages.contains_key(&message.chat) {
-        messages.insert(message.chat.clone(), DBMap::new());
+    missing
+}
+
+// the body post-message answers with when `missing_devices` finds any
+pub fn missing_devices_error(encrypted_messages: &EncryptedMessages, server: &Server) -> Option<String> {
+    let missing = missing_devices(encrypted_messages, server);
+    if missing.is_empty() {
+        return None;
     }
-    let mut chat_entries = messages.get(&message.chat).unwrap().clone();
-    chat_entries.insert(message.id.clone(), DBEntry::message(message.clone()));
-    messages.update(message.chat.clone(), chat_entries);
-}
\ No newline at end of file
+    Some(format!(
+        "{{\"server\":\"missing devices\", \"devices\":{}}}",
+        serde_json::to_string(&missing).expect("couldn't serialize devices")
+    ))
+}
+
+// advances `reader`'s read cursor in `chatid` up to `messageid` and marks every newly read message
+// as read in its sender's db, sending one read sendable per sender. returns how many messages were marked
+pub fn read_up_to(reader: &UserIdentifier, chatid: u32, messageid: u32, server: &Server) -> usize {
+    let mut newly_read: HashMap<UserIdentifier, Vec<u32>> = HashMap::new();
+    let shard = match server.user_db.get(reader) {
+        Some(shard) => shard,
+        None => return 0,
+    };
+    {
+        let mut udb = shard.lock();
+        let chat_entries = match udb.messages.get(&chatid) {
+            Some(chat_entries) => chat_entries,
+            None => return 0,
+        };
+        let up_to = match chat_entries.get(&messageid) {
+            Some(entry) => entry.get_timestamp(),
+            None => return 0,
+        };
+        let cursor = udb.read_cursors.get(&chatid).copied().unwrap_or(0);
+        if up_to <= cursor {
+            return 0;
+        }
+        // timestamp_sorted is newest first, walk it backwards so ids come out oldest first
+        for mid in chat_entries.timestamp_sorted.iter().rev() {
+            let entry = chat_entries.get(mid).unwrap();
+            let timestamp = entry.get_timestamp();
+            if timestamp <= cursor || entry.entry_type != DBEntryType::Message {
+                continue;
+            }
+            if timestamp > up_to {
+                break;
+            }
+            let message = entry.message.as_ref().unwrap();
+            if message.from_user != *reader {
+                newly_read.entry(message.from_user.clone()).or_default().push(message.id);
+            }
+        }
+        udb.read_cursors.insert(chatid, up_to);
+    }
+    let mut count = 0;
+    for (sender_uid, messageids) in newly_read {
+        count += messageids.len();
+        let sendable = read_batch("Read".to_string(), reader.username.clone(), &messageids, chatid);
+        send_sendable_from(sendable, reader, &[sender_uid.clone()], server);
+        server.user_db.with(&sender_uid, |udb| udb.edit_messages(chatid, &messageids, |message| message.read = "Read".into()));
+    }
+    count
+}
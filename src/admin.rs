@@ -0,0 +1,463 @@
+use std::{collections::HashMap, sync::Arc};
+
+use rand::Rng;
+use rocket::{State, http::ContentType, serde::json::Json};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    Server,
+    actions::send_sendable,
+    audit::{query_limit, AuditAction, AuditEntry},
+    contacts::user_by_id,
+    directory::{match_score, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE},
+    media::now_millis,
+    metrics::save_size,
+    sendables::announcement,
+    sessions::{end_session, end_user_sessions, session_token, ClientInfo, Session},
+    user::{UserIdentifier, UserProfile},
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Suspension {
+    pub reason: String,
+    // the admin's user id
+    pub by: u32,
+    pub at: u128,
+}
+
+// what operators control about an account, keyed by the immutable user id so it survives renames
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct Account {
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub suspension: Option<Suspension>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct AdminConfig {
+    // usernames promoted to admin on startup, an account has to exist before it can be listed
+    #[serde(default)]
+    pub admin_users: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Suspend {
+    pub reason: String,
+}
+
+#[derive(Deserialize)]
+pub struct Broadcast {
+    pub text: String,
+}
+
+#[derive(Serialize)]
+struct AdminUserEntry {
+    id: u32,
+    username: String,
+    name: String,
+    role: Role,
+    suspension: Option<Suspension>,
+    sessions: usize,
+}
+
+#[derive(Serialize)]
+struct AdminChatEntry {
+    id: u32,
+    name: String,
+    admin: String,
+    members: Vec<String>,
+    searchable: bool,
+}
+
+pub fn promote_admins(server: &Server, config: &AdminConfig) {
+    for username in &config.admin_users {
+        let id = server.user_id(&UserIdentifier { username: username.clone() });
+        match id {
+            Some(id) => {
+                server.accounts.lock().entry(id).or_default().role = Role::Admin;
+                info!(user_id = id, "promoted to admin from config");
+            }
+            None => warn!("admin_users lists {username:?}, which doesn't exist"),
+        }
+    }
+}
+
+pub fn is_suspended(server: &Server, uid: &UserIdentifier) -> bool {
+    match server.user_id(uid) {
+        Some(id) => server.accounts.lock().get(&id).map(|account| account.suspension.is_some()).unwrap_or(false),
+        None => false,
+    }
+}
+
+fn reply(message: &str) -> (ContentType, String) {
+    (ContentType::JSON, format!("{{\"server\":\"{}\"}}", message))
+}
+
+// the caller's user id if `token` belongs to an admin, otherwise why not
+fn admin_id(server: &Server, token: u32) -> Result<u32, &'static str> {
+    let id = server
+        .token_user(token)
+        .and_then(|uid| server.user_id(&uid))
+        .ok_or("invalid token")?;
+    match server.accounts.lock().get(&id) {
+        Some(account) if account.role == Role::Admin => Ok(id),
+        _ => Err("not an admin"),
+    }
+}
+
+fn user_by_id_cloned(server: &Server, id: u32) -> Option<UserProfile> {
+    user_by_id(&server.users.lock(), id).cloned()
+}
+
+fn audit(admin: u32, action: AuditAction, client: &ClientInfo) -> AuditEntry {
+    AuditEntry::new(action, admin).ip(client)
+}
+
+#[get("/admin/users/<token>?<q>&<page>&<per_page>")]
+pub fn admin_list_users(
+    token: u32,
+    q: Option<String>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+    client: ClientInfo,
+    server_arc: &State<Arc<Server>>,
+) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let admin = match admin_id(server, token) {
+        Ok(admin) => admin,
+        Err(message) => return reply(message),
+    };
+    let query = q.as_deref().unwrap_or("").trim().to_lowercase();
+    let page = page.unwrap_or(0);
+    let per_page = per_page.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let mut sessions: HashMap<UserIdentifier, usize> = HashMap::new();
+    for uid in server.tokens.lock().values() {
+        *sessions.entry(uid.clone()).or_default() += 1;
+    }
+    let (results, more) = {
+        let users = server.users.lock();
+        let accounts = server.accounts.lock();
+        // unlike the public directory this ignores `discoverable`
+        let mut matches: Vec<(usize, &UserIdentifier, &UserProfile)> = users
+            .iter()
+            .filter_map(|(uid, profile)| {
+                if query.is_empty() {
+                    return Some((0, uid, profile));
+                }
+                match_score(profile, &query).map(|score| (score, uid, profile))
+            })
+            .collect();
+        matches.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.2.username.cmp(&b.2.username)));
+        let results: Vec<AdminUserEntry> = matches
+            .iter()
+            .skip(page.saturating_mul(per_page))
+            .take(per_page)
+            .map(|(_, uid, profile)| {
+                let account = accounts.get(&profile.id).cloned().unwrap_or_default();
+                AdminUserEntry {
+                    id: profile.id,
+                    username: profile.username.clone(),
+                    name: profile.name.clone(),
+                    role: account.role,
+                    suspension: account.suspension,
+                    sessions: sessions.get(*uid).copied().unwrap_or(0),
+                }
+            })
+            .collect();
+        (results, matches.len() > page.saturating_add(1).saturating_mul(per_page))
+    };
+    server.audit.record(audit(admin, AuditAction::AdminListUsers, &client).target(&query));
+    (
+        ContentType::JSON,
+        format!(
+            "{{\"results\":{}, \"page\":{}, \"more\":{}}}",
+            serde_json::to_string(&results).expect("couldn't serialize users"),
+            page,
+            more
+        ),
+    )
+}
+
+#[get("/admin/sessions/<token>/<id>")]
+pub fn admin_list_sessions(token: u32, id: u32, client: ClientInfo, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let admin = match admin_id(server, token) {
+        Ok(admin) => admin,
+        Err(message) => return reply(message),
+    };
+    let uid = match user_by_id_cloned(server, id) {
+        Some(profile) => UserIdentifier { username: profile.username },
+        None => return reply("no such user"),
+    };
+    let listed: Vec<Session> = {
+        let tokens = server.tokens.lock();
+        let sessions = server.sessions.lock();
+        sessions
+            .iter()
+            .filter(|(session_token, _)| tokens.get(session_token) == Some(&uid))
+            .map(|(_, session)| session.clone())
+            .collect()
+    };
+    server.audit.record(audit(admin, AuditAction::AdminListSessions, &client).user(id));
+    (ContentType::JSON, serde_json::to_string(&listed).expect("couldn't serialize sessions"))
+}
+
+#[post("/admin/sessions/close/<token>/<id>/<session_id>")]
+pub fn admin_close_session(token: u32, id: u32, session_id: u32, client: ClientInfo, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let admin = match admin_id(server, token) {
+        Ok(admin) => admin,
+        Err(message) => return reply(message),
+    };
+    let uid = match user_by_id_cloned(server, id) {
+        Some(profile) => UserIdentifier { username: profile.username },
+        None => return reply("no such user"),
+    };
+    match session_token(server, &uid, session_id) {
+        Some(session_token) => {
+            end_session(server, session_token);
+            server.audit.record(audit(admin, AuditAction::AdminCloseSession, &client).user(id).target(session_id));
+            reply("closed")
+        }
+        None => reply("no such session"),
+    }
+}
+
+#[post("/admin/sessions/close-all/<token>/<id>")]
+pub fn admin_close_all_sessions(token: u32, id: u32, client: ClientInfo, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let admin = match admin_id(server, token) {
+        Ok(admin) => admin,
+        Err(message) => return reply(message),
+    };
+    let uid = match user_by_id_cloned(server, id) {
+        Some(profile) => UserIdentifier { username: profile.username },
+        None => return reply("no such user"),
+    };
+    let closed = end_user_sessions(server, &uid);
+    server.audit.record(audit(admin, AuditAction::AdminCloseAllSessions, &client).user(id).detail(closed));
+    (ContentType::JSON, format!("{{\"closed\":{}}}", closed))
+}
+
+// suspended accounts can't log in and lose every session they have
+#[post("/admin/suspend/<token>/<id>", data = "<suspend>")]
+pub fn admin_suspend(token: u32, id: u32, suspend: Json<Suspend>, client: ClientInfo, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let admin = match admin_id(server, token) {
+        Ok(admin) => admin,
+        Err(message) => return reply(message),
+    };
+    if id == admin {
+        return reply("can't suspend yourself");
+    }
+    let uid = match user_by_id_cloned(server, id) {
+        Some(profile) => UserIdentifier { username: profile.username },
+        None => return reply("no such user"),
+    };
+    let reason = suspend.reason.trim().to_string();
+    server.accounts.lock().entry(id).or_default().suspension = Some(Suspension {
+        reason: reason.clone(),
+        by: admin,
+        at: now_millis(),
+    });
+    let closed = end_user_sessions(server, &uid);
+    server.audit.record(audit(admin, AuditAction::AdminSuspend, &client).user(id).detail(&reason));
+    info!(user_id = id, admin, "account suspended");
+    (ContentType::JSON, format!("{{\"server\":\"suspended\", \"closed\":{}}}", closed))
+}
+
+#[post("/admin/unsuspend/<token>/<id>")]
+pub fn admin_unsuspend(token: u32, id: u32, client: ClientInfo, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let admin = match admin_id(server, token) {
+        Ok(admin) => admin,
+        Err(message) => return reply(message),
+    };
+    let lifted = server.accounts.lock().get_mut(&id).and_then(|account| account.suspension.take());
+    if lifted.is_none() {
+        return reply("not suspended");
+    }
+    server.audit.record(audit(admin, AuditAction::AdminUnsuspend, &client).user(id));
+    info!(user_id = id, admin, "account unsuspended");
+    reply("unsuspended")
+}
+
+#[get("/admin/chats/<token>?<page>&<per_page>")]
+pub fn admin_list_chats(token: u32, page: Option<usize>, per_page: Option<usize>, client: ClientInfo, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let admin = match admin_id(server, token) {
+        Ok(admin) => admin,
+        Err(message) => return reply(message),
+    };
+    let page = page.unwrap_or(0);
+    let per_page = per_page.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let mut chats: Vec<AdminChatEntry> = server
+        .chats
+        .entries()
+        .into_iter()
+        .map(|(_, chat)| {
+            let chat = chat.lock();
+            AdminChatEntry {
+                id: chat.id,
+                name: chat.name.clone(),
+                admin: chat.admin.username.clone(),
+                members: chat.users.iter().map(|user| user.username.clone()).collect(),
+                searchable: chat.searchable,
+            }
+        })
+        .collect();
+    chats.sort_by_key(|chat| chat.id);
+    let more = chats.len() > page.saturating_add(1).saturating_mul(per_page);
+    let results: Vec<AdminChatEntry> = chats.into_iter().skip(page.saturating_mul(per_page)).take(per_page).collect();
+    server.audit.record(audit(admin, AuditAction::AdminListChats, &client).target(page));
+    (
+        ContentType::JSON,
+        format!(
+            "{{\"results\":{}, \"page\":{}, \"more\":{}}}",
+            serde_json::to_string(&results).expect("couldn't serialize chats"),
+            page,
+            more
+        ),
+    )
+}
+
+// removes the chat, its invite links and every member's copy of its history
+#[post("/admin/delete-chat/<token>/<chatid>")]
+pub fn admin_delete_chat(token: u32, chatid: u32, client: ClientInfo, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let admin = match admin_id(server, token) {
+        Ok(admin) => admin,
+        Err(message) => return reply(message),
+    };
+    let chat = match server.chats.remove(&chatid) {
+        Some(chat) => chat.lock().clone(),
+        None => return reply("no such chat"),
+    };
+    server.chat_join_ids.lock().retain(|_, id| *id != chatid);
+    let mut removed_messages = 0;
+    for user in &chat.users {
+        let entries = server
+            .user_db
+            .with(user, |udb| {
+                udb.read_cursors.remove(&chatid);
+                udb.messages.remove(&chatid)
+            })
+            .flatten();
+        if let Some(entries) = entries {
+            removed_messages += entries.map.len();
+            server.search_indexes.with(user, |index| {
+                for id in entries.map.keys() {
+                    index.remove(*id);
+                }
+            });
+            let mut media = server.media.lock();
+            for entry in entries.map.values() {
+                if let Some(message) = &entry.message {
+                    media.release(message);
+                }
+            }
+        }
+    }
+    server.audit.record(audit(admin, AuditAction::AdminDeleteChat, &client).target(chatid).detail(&chat.name));
+    info!(chatid, admin, members = chat.users.len(), "chat deleted by admin");
+    (ContentType::JSON, format!("{{\"server\":\"deleted\", \"members\":{}, \"messages\":{}}}", chat.users.len(), removed_messages))
+}
+
+// sent to everyone with an open event stream, it isn't stored so offline users won't see it
+#[post("/admin/broadcast/<token>", data = "<broadcast>")]
+pub fn admin_broadcast(token: u32, broadcast: Json<Broadcast>, client: ClientInfo, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let admin = match admin_id(server, token) {
+        Ok(admin) => admin,
+        Err(message) => return reply(message),
+    };
+    let text = broadcast.text.trim();
+    if text.is_empty() {
+        return reply("empty announcement");
+    }
+    let connected: Vec<UserIdentifier> = server.event_stream_senders.lock().keys().cloned().collect();
+    let id = rand::thread_rng().gen::<u32>();
+    send_sendable(announcement(text, id), &connected, server);
+    server.audit.record(audit(admin, AuditAction::AdminBroadcast, &client).target(id).detail(text));
+    (ContentType::JSON, format!("{{\"server\":\"sent\", \"users\":{}}}", connected.len()))
+}
+
+#[get("/admin/stats/<token>")]
+pub fn admin_stats(token: u32, client: ClientInfo, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let admin = match admin_id(server, token) {
+        Ok(admin) => admin,
+        Err(message) => return reply(message),
+    };
+    let sessions = server.tokens.lock().len();
+    let users = server.users.lock().len();
+    let suspended = server.accounts.lock().values().filter(|account| account.suspension.is_some()).count();
+    let chats = server.chats.len();
+    // every recipient keeps their own copy, so this counts copies rather than sends
+    let stored_messages: usize = server
+        .user_db
+        .entries()
+        .into_iter()
+        .map(|(_, udb)| udb.lock().messages.map.values().map(|entries| entries.map.len()).sum::<usize>())
+        .sum();
+    let (blobs, blob_bytes) = {
+        let media = server.media.lock();
+        (media.blobs.len(), media.blobs.values().map(|info| info.size).sum::<u64>())
+    };
+    server.audit.record(audit(admin, AuditAction::AdminStats, &client));
+    (
+        ContentType::JSON,
+        format!(
+            "{{\"users\":{}, \"suspended\":{}, \"sessions\":{}, \"chats\":{}, \"stored_messages\":{}, \"media\":{{\"blobs\":{}, \"bytes\":{}}}, \"save_bytes\":{}}}",
+            users,
+            suspended,
+            sessions,
+            chats,
+            stored_messages,
+            blobs,
+            blob_bytes,
+            save_size("save"),
+        ),
+    )
+}
+
+// everyone's events, newest first. `user` and `action` narrow it down, pass the oldest timestamp
+// back as `before` for the next page
+#[get("/admin/audit/<token>?<limit>&<before>&<user>&<action>")]
+pub fn admin_audit(
+    token: u32,
+    limit: Option<usize>,
+    before: Option<u128>,
+    user: Option<u32>,
+    action: Option<String>,
+    client: ClientInfo,
+    server_arc: &State<Arc<Server>>,
+) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let admin = match admin_id(server, token) {
+        Ok(admin) => admin,
+        Err(message) => return reply(message),
+    };
+    let action: Option<AuditAction> = match action {
+        Some(action) => match serde_json::from_value(serde_json::Value::String(action)) {
+            Ok(action) => Some(action),
+            Err(_) => return reply("unknown action"),
+        },
+        None => None,
+    };
+    let entries = server.audit.query(query_limit(limit), before, |entry| {
+        user.map(|user| entry.actor == user || entry.user == user).unwrap_or(true) && action.map(|action| entry.action == action).unwrap_or(true)
+    });
+    server.audit.record(audit(admin, AuditAction::AdminAudit, &client));
+    (ContentType::JSON, serde_json::to_string(&entries).expect("couldn't serialize audit entries"))
+}
@@ -0,0 +1,254 @@
+use std::{ffi::OsString, fs::{self, File, OpenOptions}, io::Write, path::{Path, PathBuf}, sync::Arc};
+
+use parking_lot::Mutex;
+use rocket::{State, data::ByteUnit, http::ContentType};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{Server, media::now_millis, sessions::ClientInfo};
+
+const DEFAULT_QUERY_LIMIT: usize = 100;
+const MAX_QUERY_LIMIT: usize = 1000;
+
+#[derive(Deserialize, Clone)]
+pub struct AuditConfig {
+    // the log is rotated to audit.log.1 once it would grow past this
+    #[serde(default = "default_audit_max_size")]
+    pub audit_max_size: ByteUnit,
+    // rotated files kept, older ones are deleted
+    #[serde(default = "default_audit_keep")]
+    pub audit_keep: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            audit_max_size: default_audit_max_size(),
+            audit_keep: default_audit_keep(),
+        }
+    }
+}
+
+fn default_audit_max_size() -> ByteUnit {
+    ByteUnit::Mebibyte(10)
+}
+
+fn default_audit_keep() -> usize {
+    5
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    AccountCreated,
+    AccountDeleted,
+    Login,
+    LoginFailed,
+    TokenCreated,
+    Logout,
+    SessionRevoked,
+    PasswordChanged,
+    UsernameChanged,
+    ChatAdminChanged,
+    InviteCreated,
+    InviteUsed,
+    PfpChanged,
+    PfpDeleted,
+    AdminListUsers,
+    AdminListSessions,
+    AdminCloseSession,
+    AdminCloseAllSessions,
+    AdminSuspend,
+    AdminUnsuspend,
+    AdminListChats,
+    AdminDeleteChat,
+    AdminBroadcast,
+    AdminStats,
+    AdminAudit,
+    CliCreateUser,
+    CliResetUser,
+    CliResetPassword,
+    CliRevokeTokens,
+    CliImportDb,
+    CliRepairIndexes,
+    CliCompactJoinCodes,
+    CliCompactHistory,
+}
+
+// one line of the audit log. users can list entries where they're the actor or the user
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub timestamp: u128,
+    pub action: AuditAction,
+    // user id of whoever did it, 0 when nobody was logged in or it came from the console
+    pub actor: u32,
+    // user id of the account it was done to or concerns, 0 when there isn't one
+    #[serde(default)]
+    pub user: u32,
+    // chat id, session id, invite code or similar, depending on the action
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(action: AuditAction, actor: u32) -> Self {
+        Self { timestamp: now_millis(), action, actor, user: 0, target: None, ip: None, detail: None }
+    }
+
+    pub fn user(mut self, user: u32) -> Self {
+        self.user = user;
+        self
+    }
+
+    pub fn target(mut self, target: impl ToString) -> Self {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    pub fn ip(mut self, client: &ClientInfo) -> Self {
+        self.ip = client.ip.clone();
+        self
+    }
+
+    pub fn detail(mut self, detail: impl ToString) -> Self {
+        self.detail = Some(detail.to_string());
+        self
+    }
+}
+
+struct AuditFile {
+    file: Option<File>,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+// append only, one JSON object per line. its lock is only ever taken on its own
+pub struct AuditLog {
+    path: PathBuf,
+    state: Mutex<AuditFile>,
+}
+
+impl AuditLog {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let config = AuditConfig::default();
+        Self {
+            path: path.as_ref().to_path_buf(),
+            state: Mutex::new(AuditFile {
+                file: None,
+                size: 0,
+                max_size: config.audit_max_size.as_u64(),
+                keep: config.audit_keep,
+            }),
+        }
+    }
+
+    pub fn configure(&self, config: &AuditConfig) {
+        let mut state = self.state.lock();
+        state.max_size = config.audit_max_size.as_u64();
+        state.keep = config.audit_keep;
+    }
+
+    // `n` rotations ago, 0 is the file being written
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        if n == 0 {
+            return self.path.clone();
+        }
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&self, state: &mut AuditFile) {
+        state.file = None;
+        let _ = fs::remove_file(self.rotated_path(state.keep));
+        for n in (0..state.keep).rev() {
+            let _ = fs::rename(self.rotated_path(n), self.rotated_path(n + 1));
+        }
+        if state.keep == 0 {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    fn open(&self, state: &mut AuditFile) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        state.size = file.metadata()?.len();
+        state.file = Some(file);
+        Ok(())
+    }
+
+    pub fn record(&self, entry: AuditEntry) {
+        let line = serde_json::to_string(&entry).expect("couldn't serialize audit entry") + "\n";
+        let mut state = self.state.lock();
+        if state.file.is_none() {
+            if let Err(e) = self.open(&mut state) {
+                error!("couldn't open audit log {}: {e}", self.path.display());
+                return;
+            }
+        }
+        if state.size > 0 && state.size + line.len() as u64 > state.max_size {
+            self.rotate(&mut state);
+            if let Err(e) = self.open(&mut state) {
+                error!("couldn't open audit log {}: {e}", self.path.display());
+                return;
+            }
+        }
+        match state.file.as_mut().unwrap().write_all(line.as_bytes()) {
+            Ok(()) => state.size += line.len() as u64,
+            Err(e) => {
+                error!("couldn't write to audit log: {e}");
+                // reopened on the next entry
+                state.file = None;
+            }
+        }
+    }
+
+    // newest first, across the rotated files too, stopping at `limit` matches
+    pub fn query(&self, limit: usize, before: Option<u128>, filter: impl Fn(&AuditEntry) -> bool) -> Vec<AuditEntry> {
+        // held so nothing is rotated out from under the read
+        let state = self.state.lock();
+        let mut found = Vec::new();
+        for n in 0..=state.keep {
+            let contents = match fs::read_to_string(self.rotated_path(n)) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+            for line in contents.lines().rev() {
+                let entry: AuditEntry = match serde_json::from_str(line) {
+                    Ok(entry) => entry,
+                    Err(_) => continue,
+                };
+                if before.map(|before| entry.timestamp < before).unwrap_or(true) && filter(&entry) {
+                    found.push(entry);
+                    if found.len() >= limit {
+                        return found;
+                    }
+                }
+            }
+        }
+        found
+    }
+}
+
+pub fn query_limit(limit: Option<usize>) -> usize {
+    limit.unwrap_or(DEFAULT_QUERY_LIMIT).clamp(1, MAX_QUERY_LIMIT)
+}
+
+// the caller's own security events, pass the oldest timestamp back as `before` for the next page
+#[get("/audit/<token>?<limit>&<before>")]
+pub fn get_audit_events(token: u32, limit: Option<usize>, before: Option<u128>, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let id = match server.token_user(token).and_then(|uid| server.user_id(&uid)) {
+        Some(id) => id,
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    let entries = server.audit.query(query_limit(limit), before, |entry| entry.actor == id || entry.user == id);
+    (ContentType::JSON, serde_json::to_string(&entries).expect("couldn't serialize audit entries"))
+}
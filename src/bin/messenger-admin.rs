@@ -0,0 +1,5 @@
+// offline maintenance of the save files, see `messenger-admin help`
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    std::process::exit(messaging_server::cli::run(&args));
+}
@@ -0,0 +1,343 @@
+use std::{env, fs};
+
+use crate::{
+    Server,
+    account::new_user_id,
+    audit::{AuditAction, AuditConfig, AuditEntry},
+    retention::{compact_history, RetentionConfig},
+    sessions::end_user_sessions,
+    user::{UserIdentifier, UserProfile},
+    user_db::UserDB,
+    validation::{normalize_display_name, validate_username, username_taken, ValidationConfig},
+    write_files,
+};
+
+const USAGE: &str = "usage: messenger-admin [--data <dir>] <command> [args]
+
+works on the save files in <dir>/save (default: the current directory). the server rewrites
+every save file when it stops, so stop it before running anything that changes them
+
+commands:
+  list-users
+  create-user <username> <password> [display name]
+  reset-user <username>              end their sessions, forget their devices and clear their history
+  reset-password <username> <password>
+  revoke-tokens <username>
+  list-chats
+  export-db <username> <file>
+  import-db <username> <file>
+  check-indexes [--repair]           check every timestamp_sorted index, optionally rebuilding bad ones
+  compact-join-codes                 drop invite codes for chats that no longer exist
+  compact-history                    apply the retention limits from the config now";
+
+// whether the save files need writing
+type CommandResult = Result<bool, String>;
+
+// returns the process exit code
+pub fn run(args: &[String]) -> i32 {
+    let mut args = args;
+    if args.first().map(String::as_str) == Some("--data") {
+        let dir = match args.get(1) {
+            Some(dir) => dir,
+            None => return usage(),
+        };
+        if let Err(e) = env::set_current_dir(dir) {
+            eprintln!("can't use {dir}: {e}");
+            return 1;
+        }
+        args = &args[2..];
+    }
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (command.as_str(), args),
+        None => return usage(),
+    };
+    if command == "help" || command == "--help" {
+        println!("{USAGE}");
+        return 0;
+    }
+    let server = Server::from_file();
+    if let Ok(config) = rocket::Config::figment().extract::<AuditConfig>() {
+        server.audit.configure(&config);
+    }
+    let result = match command {
+        "list-users" => list_users(&server),
+        "create-user" => create_user(&server, args),
+        "reset-user" => reset_user(&server, args),
+        "reset-password" => reset_password(&server, args),
+        "revoke-tokens" => revoke_tokens(&server, args),
+        "list-chats" => list_chats(&server),
+        "export-db" => export_db(&server, args),
+        "import-db" => import_db(&server, args),
+        "check-indexes" => check_indexes(&server, args),
+        "compact-join-codes" => compact_join_codes(&server),
+        "compact-history" => compact_history_now(&server),
+        _ => return usage(),
+    };
+    match result {
+        Ok(false) => 0,
+        Ok(true) => match write_files(&server) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("couldn't write the save files: {e}");
+                1
+            }
+        },
+        Err(e) => {
+            eprintln!("{e}");
+            1
+        }
+    }
+}
+
+fn usage() -> i32 {
+    eprintln!("{USAGE}");
+    2
+}
+
+fn arg<'a>(args: &'a [String], index: usize, name: &str) -> Result<&'a str, String> {
+    args.get(index).map(String::as_str).ok_or_else(|| format!("missing <{name}>\n\n{USAGE}"))
+}
+
+// the user's uid and immutable id
+fn existing_user(server: &Server, username: &str) -> Result<(UserIdentifier, u32), String> {
+    let uid = UserIdentifier { username: username.to_string() };
+    match server.user_id(&uid) {
+        Some(id) => Ok((uid, id)),
+        None => Err(format!("no user {username:?}")),
+    }
+}
+
+// done from the console, so there's no acting user or ip
+fn console(action: AuditAction) -> AuditEntry {
+    AuditEntry::new(action, 0)
+}
+
+fn list_users(server: &Server) -> CommandResult {
+    let users = server.users.lock();
+    let accounts = server.accounts.lock();
+    let mut profiles: Vec<&UserProfile> = users.values().collect();
+    profiles.sort_by(|a, b| a.username.cmp(&b.username));
+    for profile in profiles {
+        let account = accounts.get(&profile.id).cloned().unwrap_or_default();
+        println!(
+            "{}\t{}\t{}\t{:?}{}",
+            profile.id,
+            profile.username,
+            profile.name,
+            account.role,
+            if account.suspension.is_some() { "\tsuspended" } else { "" },
+        );
+    }
+    Ok(false)
+}
+
+fn create_user(server: &Server, args: &[String]) -> CommandResult {
+    let username = arg(args, 0, "username")?;
+    let password = arg(args, 1, "password")?;
+    let validation: ValidationConfig = rocket::Config::figment().extract().map_err(|e| format!("bad config: {e}"))?;
+    validate_username(username, &validation).map_err(|e| format!("username {e}"))?;
+    if password.is_empty() {
+        return Err("empty password".to_string());
+    }
+    let name = normalize_display_name(args.get(2).map(String::as_str).unwrap_or(username), &validation).map_err(|e| format!("display name {e}"))?;
+    let uid = UserIdentifier { username: username.to_string() };
+    let id = {
+        let mut users = server.users.lock();
+        if username_taken(username, &users, None) {
+            return Err(format!("{username:?} is taken"));
+        }
+        let id = new_user_id(&users);
+        users.insert(uid.clone(), UserProfile {
+            id,
+            username: username.to_string(),
+            name,
+            color: "#808080".to_string(),
+            pfp: "undefined".to_string(),
+            public_key: String::new(),
+            discoverable: true,
+        });
+        id
+    };
+    server.passwords.lock().insert(uid.clone(), password.to_string());
+    server.user_db.insert(uid, UserDB::new());
+    server.audit.record(console(AuditAction::CliCreateUser).user(id));
+    println!("created {username} with id {id}");
+    Ok(true)
+}
+
+fn reset_user(server: &Server, args: &[String]) -> CommandResult {
+    let (uid, id) = existing_user(server, arg(args, 0, "username")?)?;
+    let revoked = end_user_sessions(server, &uid);
+    server.keys.lock().remove(&uid);
+    if let Some(udb) = server.user_db.get(&uid) {
+        let mut udb = udb.lock();
+        let mut media = server.media.lock();
+        for entries in udb.messages.map.values() {
+            for entry in entries.map.values() {
+                if let Some(message) = &entry.message {
+                    media.release(message);
+                }
+            }
+        }
+        *udb = UserDB::new();
+    }
+    server.audit.record(console(AuditAction::CliResetUser).user(id));
+    println!("reset {}, revoked {revoked} sessions", uid.username);
+    Ok(true)
+}
+
+fn reset_password(server: &Server, args: &[String]) -> CommandResult {
+    let (uid, id) = existing_user(server, arg(args, 0, "username")?)?;
+    let password = arg(args, 1, "password")?;
+    if password.is_empty() {
+        return Err("empty password".to_string());
+    }
+    server.passwords.lock().insert(uid.clone(), password.to_string());
+    let revoked = end_user_sessions(server, &uid);
+    server.audit.record(console(AuditAction::CliResetPassword).user(id));
+    println!("password changed, revoked {revoked} sessions");
+    Ok(true)
+}
+
+fn revoke_tokens(server: &Server, args: &[String]) -> CommandResult {
+    let (uid, id) = existing_user(server, arg(args, 0, "username")?)?;
+    let revoked = end_user_sessions(server, &uid);
+    server.audit.record(console(AuditAction::CliRevokeTokens).user(id).detail(revoked));
+    println!("revoked {revoked} sessions");
+    Ok(revoked > 0)
+}
+
+fn list_chats(server: &Server) -> CommandResult {
+    let mut chats: Vec<_> = server.chats.snapshot().into_values().collect();
+    chats.sort_by_key(|chat| chat.id);
+    for chat in chats {
+        let members: Vec<&str> = chat.users.iter().map(|user| user.username.as_str()).collect();
+        println!("{}\t{}\t{}\t{}", chat.id, chat.name, chat.admin.username, members.join(","));
+    }
+    Ok(false)
+}
+
+fn export_db(server: &Server, args: &[String]) -> CommandResult {
+    let (uid, _) = existing_user(server, arg(args, 0, "username")?)?;
+    let file = arg(args, 1, "file")?;
+    let json = server
+        .user_db
+        .with(&uid, |udb| serde_json::to_string_pretty(udb).expect("couldn't serialize user db"))
+        .ok_or_else(|| format!("{} has no user db", uid.username))?;
+    fs::write(file, json).map_err(|e| format!("couldn't write {file}: {e}"))?;
+    println!("exported {} to {file}", uid.username);
+    Ok(false)
+}
+
+// replaces the user's history. chats they aren't in are kept, but only they will see them
+fn import_db(server: &Server, args: &[String]) -> CommandResult {
+    let (uid, id) = existing_user(server, arg(args, 0, "username")?)?;
+    let file = arg(args, 1, "file")?;
+    let json = fs::read_to_string(file).map_err(|e| format!("couldn't read {file}: {e}"))?;
+    let mut udb: UserDB = serde_json::from_str(&json).map_err(|e| format!("couldn't parse {file}: {e}"))?;
+    if repair_indexes(&uid, &mut udb, true) > 0 {
+        println!("rebuilt the indexes above");
+    }
+    for chatid in udb.messages.map.keys() {
+        let member = server.chats.with(chatid, |chat| chat.users.contains(&uid));
+        if member != Some(true) {
+            println!("warning: {} isn't in chat {chatid}", uid.username);
+        }
+    }
+    if let Some(old) = server.user_db.get(&uid) {
+        let old = old.lock();
+        let mut media = server.media.lock();
+        for entries in old.messages.map.values() {
+            for entry in entries.map.values() {
+                if let Some(message) = &entry.message {
+                    media.release(message);
+                }
+            }
+        }
+    }
+    server.user_db.insert(uid.clone(), udb);
+    server.audit.record(console(AuditAction::CliImportDb).user(id));
+    println!("imported {file} for {}", uid.username);
+    Ok(true)
+}
+
+// prints every bad index and returns how many there were. chats go first since the outer index
+// is sorted by each chat's newest entry
+fn repair_indexes(uid: &UserIdentifier, udb: &mut UserDB, repair: bool) -> usize {
+    let mut bad = 0;
+    let mut chatids: Vec<u32> = udb.messages.map.keys().copied().collect();
+    chatids.sort();
+    for chatid in chatids {
+        let entries = udb.messages.map.get_mut(&chatid).unwrap();
+        let problems = entries.index_problems();
+        if !problems.is_empty() {
+            bad += 1;
+            println!("{} chat {chatid}: {}", uid.username, problems.join(", "));
+            if repair {
+                entries.rebuild_index();
+            }
+        }
+    }
+    let problems = udb.messages.index_problems();
+    if !problems.is_empty() {
+        bad += 1;
+        println!("{} chats: {}", uid.username, problems.join(", "));
+    }
+    if repair && bad > 0 {
+        udb.messages.rebuild_index();
+    }
+    bad
+}
+
+fn check_indexes(server: &Server, args: &[String]) -> CommandResult {
+    let repair = args.iter().any(|arg| arg == "--repair");
+    let mut bad = 0;
+    let mut entries = server.user_db.entries();
+    entries.sort_by(|a, b| a.0.username.cmp(&b.0.username));
+    for (uid, udb) in entries {
+        bad += repair_indexes(&uid, &mut udb.lock(), repair);
+    }
+    match (bad, repair) {
+        (0, _) => {
+            println!("all indexes are fine");
+            Ok(false)
+        }
+        (_, true) => {
+            server.audit.record(console(AuditAction::CliRepairIndexes).detail(bad));
+            println!("rebuilt {bad} indexes");
+            Ok(true)
+        }
+        (_, false) => Err(format!("{bad} indexes need rebuilding, run again with --repair")),
+    }
+}
+
+// invite codes don't expire, but ones for deleted chats can't be used
+fn compact_join_codes(server: &Server) -> CommandResult {
+    let chatids: Vec<u32> = server.chat_join_ids.lock().values().copied().collect();
+    let gone: Vec<u32> = chatids.into_iter().filter(|chatid| !server.chats.contains_key(chatid)).collect();
+    let removed = {
+        let mut chat_join_ids = server.chat_join_ids.lock();
+        let before = chat_join_ids.len();
+        chat_join_ids.retain(|_, chatid| !gone.contains(chatid));
+        before - chat_join_ids.len()
+    };
+    if removed > 0 {
+        server.audit.record(console(AuditAction::CliCompactJoinCodes).detail(removed));
+    }
+    println!("removed {removed} join codes");
+    Ok(removed > 0)
+}
+
+// shrinks the save files without waiting for the server's next compaction
+fn compact_history_now(server: &Server) -> CommandResult {
+    let config: RetentionConfig = rocket::Config::figment().extract().map_err(|e| format!("bad config: {e}"))?;
+    if !config.enabled() {
+        return Err("no retention limits are configured".to_string());
+    }
+    let removed = compact_history(server, &config);
+    if removed > 0 {
+        server.audit.record(console(AuditAction::CliCompactHistory).detail(removed));
+    }
+    println!("removed {removed} history entries");
+    Ok(removed > 0)
+}
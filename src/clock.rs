@@ -0,0 +1,39 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// where time comes from for anything that counts it down, so limits and expiries can be driven by a fake clock
+pub trait Clock: Send + Sync {
+    fn now_millis(&self) -> u128;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u128 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis()
+    }
+}
+
+// a clock that only moves when told to
+#[cfg(test)]
+pub struct FakeClock(parking_lot::Mutex<u128>);
+
+#[cfg(test)]
+impl FakeClock {
+    pub fn new(now: u128) -> std::sync::Arc<Self> {
+        std::sync::Arc::new(Self(parking_lot::Mutex::new(now)))
+    }
+
+    pub fn advance(&self, millis: u128) {
+        *self.0.lock() += millis;
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now_millis(&self) -> u128 {
+        *self.0.lock()
+    }
+}
@@ -0,0 +1,167 @@
+use std::{collections::HashMap, sync::Arc};
+
+use rocket::{State, http::ContentType};
+use serde::{Deserialize, Serialize};
+
+use crate::{Server, directory::DirectoryEntry, media::now_millis, user::{UserIdentifier, UserProfile}};
+
+// someone a user saved, keyed by the other user's id so it survives them renaming
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Contact {
+    pub label: Option<String>,
+    #[serde(default)]
+    pub blocked: bool,
+    pub added: u128,
+}
+
+pub fn user_by_id(users: &HashMap<UserIdentifier, UserProfile>, id: u32) -> Option<&UserProfile> {
+    users.values().find(|profile| profile.id == id)
+}
+
+// whether `blocker` has blocked `sender`. deliveries from a blocked sender are dropped without telling them
+pub fn is_blocked(server: &Server, blocker: &UserIdentifier, sender: &UserIdentifier) -> bool {
+    if blocker == sender {
+        return false;
+    }
+    let sender_id = match server.users.lock().get(sender) {
+        Some(profile) => profile.id,
+        None => return false,
+    };
+    server
+        .contacts
+        .lock()
+        .get(blocker)
+        .and_then(|contacts| contacts.get(&sender_id))
+        .map(|contact| contact.blocked)
+        .unwrap_or(false)
+}
+
+// runs `edit` on the caller's contact entry for `id`, creating it if needed
+fn edit_contact(token: u32, id: u32, server: &Server, edit: impl FnOnce(&mut Contact)) -> (ContentType, String) {
+    let uid = match server.token_user(token) {
+        Some(uid) => uid,
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    let own_id = match server.users.lock().get(&uid) {
+        Some(profile) => profile.id,
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    if id == own_id || user_by_id(&server.users.lock(), id).is_none() {
+        return (ContentType::JSON, "{\"server\":\"no user\"}".into());
+    }
+    let mut contacts = server.contacts.lock();
+    let contact = contacts
+        .entry(uid)
+        .or_default()
+        .entry(id)
+        .or_insert_with(|| Contact { label: None, blocked: false, added: now_millis() });
+    edit(contact);
+    (ContentType::JSON, "{\"server\":\"updated\"}".into())
+}
+
+#[get("/contacts/<token>")]
+pub fn get_contacts(token: u32, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let uid = match server.token_user(token) {
+        Some(uid) => uid,
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    let users = server.users.lock();
+    let contacts = server.contacts.lock();
+    let mut listed = Vec::new();
+    for (id, contact) in contacts.get(&uid).into_iter().flatten() {
+        if let Some(profile) = user_by_id(&users, *id) {
+            listed.push(format!(
+                "{{\"user\":{}, \"label\":{}, \"blocked\":{}, \"added\":{}}}",
+                serde_json::to_string(&DirectoryEntry::from_profile(profile)).expect("couldn't serialize contact"),
+                serde_json::to_string(&contact.label).unwrap(),
+                contact.blocked,
+                contact.added
+            ));
+        }
+    }
+    (ContentType::JSON, format!("[{}]", listed.join(",")))
+}
+
+#[post("/contacts/add/<token>/<username>")]
+pub fn add_contact(token: u32, username: String, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let id = match server.users.lock().get(&UserIdentifier { username }) {
+        Some(profile) => profile.id,
+        None => return (ContentType::JSON, "{\"server\":\"no user\"}".into()),
+    };
+    edit_contact(token, id, server, |_| {})
+}
+
+#[post("/contacts/label/<token>/<id>", data = "<label>")]
+pub fn label_contact(token: u32, id: u32, label: String, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let label = label.trim().to_string();
+    edit_contact(token, id, server, |contact| contact.label = if label.is_empty() { None } else { Some(label) })
+}
+
+#[post("/contacts/block/<token>/<id>")]
+pub fn block_contact(token: u32, id: u32, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    edit_contact(token, id, server, |contact| contact.blocked = true)
+}
+
+#[post("/contacts/unblock/<token>/<id>")]
+pub fn unblock_contact(token: u32, id: u32, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    edit_contact(token, id, server, |contact| contact.blocked = false)
+}
+
+#[post("/contacts/remove/<token>/<id>")]
+pub fn remove_contact(token: u32, id: u32, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let uid = match server.token_user(token) {
+        Some(uid) => uid,
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    let removed = server.contacts.lock().get_mut(&uid).and_then(|contacts| contacts.remove(&id));
+    match removed {
+        Some(_) => (ContentType::JSON, "{\"server\":\"removed\"}".into()),
+        None => (ContentType::JSON, "{\"server\":\"no contact\"}".into()),
+    }
+}
+
+#[get("/blocked/<token>")]
+pub fn get_blocked(token: u32, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let uid = match server.token_user(token) {
+        Some(uid) => uid,
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    let users = server.users.lock();
+    let blocked: Vec<DirectoryEntry> = server
+        .contacts
+        .lock()
+        .get(&uid)
+        .into_iter()
+        .flatten()
+        .filter(|(_, contact)| contact.blocked)
+        .filter_map(|(id, _)| user_by_id(&users, *id).map(DirectoryEntry::from_profile))
+        .collect();
+    (ContentType::JSON, serde_json::to_string(&blocked).expect("couldn't serialize blocked users"))
+}
+
+#[post("/block/<token>/<username>")]
+pub fn block_user(token: u32, username: String, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let id = match server.users.lock().get(&UserIdentifier { username }) {
+        Some(profile) => profile.id,
+        None => return (ContentType::JSON, "{\"server\":\"no user\"}".into()),
+    };
+    edit_contact(token, id, server, |contact| contact.blocked = true)
+}
+
+#[post("/unblock/<token>/<username>")]
+pub fn unblock_user(token: u32, username: String, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let id = match server.users.lock().get(&UserIdentifier { username }) {
+        Some(profile) => profile.id,
+        None => return (ContentType::JSON, "{\"server\":\"no user\"}".into()),
+    };
+    edit_contact(token, id, server, |contact| contact.blocked = false)
+}
@@ -0,0 +1,182 @@
+use std::{collections::HashMap, sync::Arc};
+
+use rocket::{State, http::ContentType};
+use serde::Serialize;
+
+use crate::{Server, media::now_millis, user::{UserIdentifier, UserProfile}};
+
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+pub const MAX_PAGE_SIZE: usize = 50;
+const MAX_FUZZY_DISTANCE: usize = 2;
+const SEARCHES_PER_WINDOW: usize = 30;
+const SEARCH_WINDOW_MILLIS: u128 = 60 * 1000;
+
+// sliding window of recent searches per user, directory search is the easiest way to enumerate accounts
+#[derive(Default)]
+pub struct SearchLimiter {
+    recent: HashMap<UserIdentifier, Vec<u128>>,
+    pruned: u128,
+}
+
+impl SearchLimiter {
+    pub fn allow(&mut self, uid: &UserIdentifier) -> bool {
+        self.allow_at(uid, now_millis())
+    }
+
+    fn allow_at(&mut self, uid: &UserIdentifier, now: u128) -> bool {
+        // users with nothing left in their window are dropped, at most once a window so it stays cheap
+        if now.saturating_sub(self.pruned) >= SEARCH_WINDOW_MILLIS {
+            self.recent.retain(|_, recent| recent.iter().any(|at| now.saturating_sub(*at) < SEARCH_WINDOW_MILLIS));
+            self.pruned = now;
+        }
+        let recent = self.recent.entry(uid.clone()).or_default();
+        recent.retain(|at| now.saturating_sub(*at) < SEARCH_WINDOW_MILLIS);
+        if recent.len() >= SEARCHES_PER_WINDOW {
+            return false;
+        }
+        recent.push(now);
+        true
+    }
+}
+
+#[derive(Serialize)]
+pub struct DirectoryEntry {
+    pub id: u32,
+    pub username: String,
+    pub name: String,
+    pub color: String,
+    pub pfp: String,
+}
+
+impl DirectoryEntry {
+    pub fn from_profile(profile: &UserProfile) -> Self {
+        Self {
+            id: profile.id,
+            username: profile.username.clone(),
+            name: profile.name.clone(),
+            color: profile.color.clone(),
+            pfp: profile.pfp.clone(),
+        }
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// lower is better, None means no match. exact username, then prefixes, then typos
+pub fn match_score(profile: &UserProfile, query: &str) -> Option<usize> {
+    let username = profile.username.to_lowercase();
+    let name = profile.name.to_lowercase();
+    if username == query {
+        return Some(0);
+    }
+    if username.starts_with(query) {
+        return Some(1);
+    }
+    if name.starts_with(query) || name.split_whitespace().any(|word| word.starts_with(query)) {
+        return Some(2);
+    }
+    // short queries match almost everything within a couple of edits
+    if query.chars().count() < 3 {
+        return None;
+    }
+    let prefix: String = username.chars().take(query.chars().count()).collect();
+    let distance = edit_distance(query, &username).min(edit_distance(query, &prefix));
+    if distance <= MAX_FUZZY_DISTANCE {
+        return Some(3 + distance);
+    }
+    None
+}
+
+#[get("/users/search/<token>?<q>&<page>&<per_page>")]
+pub fn search_users(
+    token: u32,
+    q: String,
+    page: Option<usize>,
+    per_page: Option<usize>,
+    server_arc: &State<Arc<Server>>,
+) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let uid = match server.tokens.lock().get(&token) {
+        Some(uid) => uid.clone(),
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    if !server.search_limiter.lock().allow(&uid) {
+        return (ContentType::JSON, "{\"server\":\"too many searches\"}".into());
+    }
+    let query = q.trim().to_lowercase();
+    if query.is_empty() {
+        return (ContentType::JSON, "{\"server\":\"empty query\"}".into());
+    }
+    let page = page.unwrap_or(0);
+    let per_page = per_page.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let users = server.users.lock();
+    let mut matches: Vec<(usize, &UserProfile)> = users
+        .iter()
+        // people who opted out of discovery can still be found by their exact username
+        .filter(|(other, profile)| **other != uid && (profile.discoverable || profile.username.to_lowercase() == query))
+        .filter_map(|(_, profile)| match_score(profile, &query).map(|score| (score, profile)))
+        .collect();
+    matches.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.username.cmp(&b.1.username)));
+    let results: Vec<DirectoryEntry> = matches
+        .iter()
+        .skip(page.saturating_mul(per_page))
+        .take(per_page)
+        .map(|(_, profile)| DirectoryEntry::from_profile(profile))
+        .collect();
+    let more = matches.len() > page.saturating_add(1).saturating_mul(per_page);
+    (
+        ContentType::JSON,
+        format!(
+            "{{\"results\":{}, \"page\":{}, \"more\":{}}}",
+            serde_json::to_string(&results).expect("couldn't serialize directory results"),
+            page,
+            more
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SEARCH_WINDOW_MILLIS, SEARCHES_PER_WINDOW, SearchLimiter};
+    use crate::user::UserIdentifier;
+
+    fn user(n: usize) -> UserIdentifier {
+        UserIdentifier { username: format!("user{}", n) }
+    }
+
+    #[test]
+    fn searches_are_limited_per_window() {
+        let mut limiter = SearchLimiter::default();
+        for _ in 0..SEARCHES_PER_WINDOW {
+            assert!(limiter.allow_at(&user(0), 1_000));
+        }
+        assert!(!limiter.allow_at(&user(0), 1_000));
+        assert!(limiter.allow_at(&user(1), 1_000));
+        assert!(limiter.allow_at(&user(0), 1_000 + SEARCH_WINDOW_MILLIS));
+    }
+
+    #[test]
+    fn users_whose_window_emptied_are_dropped() {
+        let mut limiter = SearchLimiter::default();
+        for n in 0..100 {
+            limiter.allow_at(&user(n), 1_000);
+        }
+        assert_eq!(limiter.recent.len(), 100);
+        limiter.allow_at(&user(0), 1_000 + SEARCH_WINDOW_MILLIS / 2);
+        assert_eq!(limiter.recent.len(), 100);
+        limiter.allow_at(&user(0), 1_000 + SEARCH_WINDOW_MILLIS * 2);
+        assert_eq!(limiter.recent.len(), 1);
+    }
+}
@@ -0,0 +1,114 @@
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{
+    Server,
+    actions::send_sendable,
+    media::now_millis,
+    message::Message,
+    sendables::deleted,
+    user::UserIdentifier,
+    user_db::DBEntry,
+};
+
+const MIN_EXPIRE_AFTER: u64 = 60;
+const MAX_EXPIRE_AFTER: u64 = 365 * 24 * 60 * 60;
+
+#[derive(Deserialize, Clone)]
+pub struct ExpiryConfig {
+    // seconds between sweeps, messages can outlive their expiry by up to this long
+    #[serde(default = "default_expiry_sweep_interval")]
+    pub expiry_sweep_interval: u64,
+}
+
+impl Default for ExpiryConfig {
+    fn default() -> Self {
+        Self {
+            expiry_sweep_interval: default_expiry_sweep_interval(),
+        }
+    }
+}
+
+fn default_expiry_sweep_interval() -> u64 {
+    60
+}
+
+pub fn validate_expire_after(seconds: u64) -> Result<(), String> {
+    if !(MIN_EXPIRE_AFTER..=MAX_EXPIRE_AFTER).contains(&seconds) {
+        return Err(format!("must be between {} and {}", describe_duration(MIN_EXPIRE_AFTER), describe_duration(MAX_EXPIRE_AFTER)));
+    }
+    Ok(())
+}
+
+// in the largest unit that fits exactly, "1 hour" or "90 minutes"
+pub fn describe_duration(seconds: u64) -> String {
+    for (unit, name) in [(7 * 24 * 60 * 60, "week"), (24 * 60 * 60, "day"), (60 * 60, "hour"), (60, "minute")] {
+        if seconds >= unit && seconds.is_multiple_of(unit) {
+            let count = seconds / unit;
+            return format!("{} {}{}", count, name, if count == 1 { "" } else { "s" });
+        }
+    }
+    format!("{} second{}", seconds, if seconds == 1 { "" } else { "s" })
+}
+
+// when a message sent to `chatid` now should expire
+pub fn expires_at(server: &Server, chatid: u32) -> Option<u128> {
+    let expire_after = server.chats.with(&chatid, |chat| chat.expire_after).flatten()?;
+    Some(now_millis() + expire_after as u128 * 1000)
+}
+
+fn expired(entry: &DBEntry, now: u128) -> bool {
+    entry.message.as_ref().and_then(|message| message.expires_at).map(|expires_at| expires_at <= now).unwrap_or(false)
+}
+
+// removes expired messages from every UserDB and tells that user's open streams, returns how many
+// copies were removed
+pub fn sweep_expired(server: &Server) -> usize {
+    let now = now_millis();
+    let mut total = 0;
+    for (uid, shard) in server.user_db.entries() {
+        let removed: Vec<(u32, Vec<Message>)> = {
+            let mut udb = shard.lock();
+            let chatids: Vec<u32> = udb
+                .messages
+                .map
+                .iter()
+                .filter(|(_, entries)| entries.map.values().any(|entry| expired(entry, now)))
+                .map(|(chatid, _)| *chatid)
+                .collect();
+            let mut removed = Vec::new();
+            for chatid in chatids {
+                let mut entries = udb.messages.get(&chatid).unwrap().clone();
+                let ids: Vec<u32> = entries.map.iter().filter(|(_, entry)| expired(entry, now)).map(|(id, _)| *id).collect();
+                let messages: Vec<Message> = ids.iter().filter_map(|id| entries.remove(id)).filter_map(|entry| entry.message).collect();
+                udb.messages.update(chatid, entries);
+                removed.push((chatid, messages));
+            }
+            removed
+        };
+        for (chatid, messages) in removed {
+            total += messages.len();
+            forget_messages(server, &uid, &messages);
+            let ids: Vec<u32> = messages.iter().map(|message| message.id).collect();
+            send_sendable(deleted(&ids, chatid), &vec![uid.clone()], server);
+        }
+    }
+    if total > 0 {
+        info!(messages = total, "removed expired messages");
+    }
+    total
+}
+
+// drops messages already removed from `uid`'s UserDB from their search index and media refcounts.
+// takes those locks itself, so call it once the UserDB lock is released
+pub fn forget_messages(server: &Server, uid: &UserIdentifier, messages: &[Message]) {
+    server.search_indexes.with(uid, |index| {
+        for message in messages {
+            index.remove(message.id);
+        }
+    });
+    let mut media = server.media.lock();
+    for message in messages {
+        media.release(message);
+    }
+}
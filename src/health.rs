@@ -0,0 +1,131 @@
+use std::{fs, path::Path, sync::{Arc, atomic::{AtomicBool, Ordering}}, time::{Duration, Instant}};
+
+use rocket::{State, http::{ContentType, Status}, tokio::{task::JoinHandle, time}};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{Server, actions::send_sendable, media::MediaConfig, sendables::reconnect};
+
+#[derive(Deserialize, Clone)]
+pub struct ShutdownConfig {
+    // how long the websocket server gets to drain once rocket has stopped, rocket's own
+    // `shutdown.grace` and `shutdown.mercy` bound the http side
+    #[serde(default = "default_shutdown_deadline")]
+    pub shutdown_deadline: u64,
+    // what clients are told to wait before reconnecting
+    #[serde(default = "default_reconnect_after")]
+    pub reconnect_after: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            shutdown_deadline: default_shutdown_deadline(),
+            reconnect_after: default_reconnect_after(),
+        }
+    }
+}
+
+fn default_shutdown_deadline() -> u64 {
+    10
+}
+
+fn default_reconnect_after() -> u64 {
+    5
+}
+
+// listener and shutdown state, atomics only so it sits outside the lock order
+pub struct Health {
+    started: Instant,
+    rocket_listening: AtomicBool,
+    warp_listening: AtomicBool,
+    shutting_down: AtomicBool,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            rocket_listening: AtomicBool::new(false),
+            warp_listening: AtomicBool::new(false),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Health {
+    pub fn shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Acquire)
+    }
+
+    pub fn set_rocket_listening(&self, listening: bool) {
+        self.rocket_listening.store(listening, Ordering::Release);
+    }
+
+    pub fn set_warp_listening(&self, listening: bool) {
+        self.warp_listening.store(listening, Ordering::Release);
+    }
+}
+
+// tells every connected client to come back in `reconnect_after` seconds, then drops the senders so
+// the sse loops and websockets end on their own instead of holding up the drain
+pub fn close_event_streams(server: &Server, reconnect_after: u64) {
+    server.health.shutting_down.store(true, Ordering::Release);
+    let connected: Vec<_> = server.event_stream_senders.lock().keys().cloned().collect();
+    send_sendable(reconnect(reconnect_after * 1000), &connected, server);
+    let closed: usize = server.event_stream_senders.lock().drain().map(|(_, senders)| senders.len()).sum();
+    info!(streams = closed, "closed event streams for shutdown");
+}
+
+// waits for warp to stop listening and then for its websockets to close, which warp doesn't track
+// once they're upgraded. gives up at `deadline`
+pub async fn drain_websockets(server: &Server, warp: JoinHandle<()>, deadline: Duration) {
+    let deadline = time::Instant::now() + deadline;
+    if time::timeout_at(deadline, warp).await.is_err() {
+        warn!("websocket server didn't stop in time");
+        return;
+    }
+    while server.metrics.open_streams("websocket") > 0 {
+        if time::Instant::now() >= deadline {
+            warn!(open = server.metrics.open_streams("websocket"), "websockets didn't close in time");
+            return;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+// a file can be created and removed in `dir`
+fn writable(dir: &Path) -> bool {
+    let probe = dir.join(".ready");
+    fs::create_dir_all(dir).is_ok() && fs::write(&probe, b"").is_ok() && fs::remove_file(&probe).is_ok()
+}
+
+#[get("/healthz")]
+pub fn healthz(server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    (ContentType::JSON, format!("{{\"status\":\"ok\", \"uptime\":{}}}", server.health.started.elapsed().as_secs()))
+}
+
+// 503 while shutting down or when a listener is down or storage can't be written
+#[get("/readyz")]
+pub fn readyz(config: &State<MediaConfig>, server_arc: &State<Arc<Server>>) -> (Status, (ContentType, String)) {
+    let server: &Server = server_arc;
+    let health = &server.health;
+    let rocket = health.rocket_listening.load(Ordering::Acquire);
+    let warp = health.warp_listening.load(Ordering::Acquire);
+    let save = writable(Path::new("save"));
+    let media = writable(Path::new(&config.media_root));
+    let shutting_down = health.shutting_down();
+    let ready = rocket && warp && save && media && !shutting_down;
+    let body = format!(
+        "{{\"status\":\"{}\", \"listeners\":{{\"http\":{}, \"websocket\":{}}}, \"storage\":{{\"save\":{}, \"media\":{}}}, \"shutting_down\":{}}}",
+        if ready { "ready" } else { "unavailable" },
+        rocket,
+        warp,
+        save,
+        media,
+        shutting_down,
+    );
+    let status = if ready { Status::Ok } else { Status::ServiceUnavailable };
+    (status, (ContentType::JSON, body))
+}
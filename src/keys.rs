@@ -0,0 +1,390 @@
+use std::{collections::HashMap, sync::Arc};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use rocket::{State, http::ContentType, serde::json::Json};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{Server, actions::send_sendable, sessions::{bind_session_device, end_session, session_device}, media::now_millis, sendables::key_changed, user::UserIdentifier, user_db::DBEntry};
+
+// a key a single device publishes. `public_key` is what other clients encrypt to and is opaque to
+// the server, `identity_key` is a base64 ed25519 key the device signs its key rotations with
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeviceKey {
+    pub device_id: u32,
+    pub name: String,
+    pub public_key: String,
+    pub identity_key: Option<String>,
+    pub created: u128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyChange {
+    pub device_id: u32,
+    pub old_key: Option<String>,
+    pub new_key: Option<String>,
+    pub signature: Option<String>,
+    pub timestamp: u128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct UserKeys {
+    pub devices: Vec<DeviceKey>,
+    pub history: Vec<KeyChange>,
+}
+
+impl UserKeys {
+    pub fn device(&self, device_id: u32) -> Option<&DeviceKey> {
+        self.devices.iter().find(|device| device.device_id == device_id)
+    }
+
+    // covers every device so adding, removing or rotating any of them changes it
+    pub fn fingerprint(&self) -> String {
+        let mut keys: Vec<String> = self
+            .devices
+            .iter()
+            .map(|device| format!("{}:{}", device.identity_key.as_deref().unwrap_or(""), device.public_key))
+            .collect();
+        keys.sort();
+        let digest = Sha256::digest(keys.join("\n").as_bytes());
+        digest
+            .chunks(2)
+            .take(8)
+            .map(|chunk| format!("{:02x}{:02x}", chunk[0], chunk[1]))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+pub fn key_fingerprint(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// 30 digits per user derived from their fingerprint, ordered by user id so both sides see the same
+// number. the id never changes, so a rename doesn't look like a key change
+pub fn safety_number(a: (u32, &UserKeys), b: (u32, &UserKeys)) -> String {
+    let digits = |user_id: u32, keys: &UserKeys| {
+        let digest = Sha256::digest(format!("{}\n{}", user_id, keys.fingerprint()).as_bytes());
+        digest
+            .chunks(5)
+            .take(6)
+            .map(|chunk| {
+                let value = chunk.iter().fold(0u64, |value, byte| (value << 8) | *byte as u64);
+                format!("{:05}", value % 100000)
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    };
+    let mut halves = [(a.0, digits(a.0, a.1)), (b.0, digits(b.0, b.1))];
+    halves.sort_by_key(|half| half.0);
+    format!("{} {}", halves[0].1, halves[1].1)
+}
+
+// what a rotation signature covers. the device and the key being replaced are bound in, so a
+// published signature can't be replayed on another device or to roll a device back
+pub fn rotation_message(device_id: u32, previous_key: &str, new_key: &str) -> String {
+    format!("{}\n{}\n{}", device_id, previous_key, new_key)
+}
+
+// what an existing device signs to vouch for a new one
+pub fn addition_message(public_key: &str, identity_key: Option<&str>) -> String {
+    format!("add\n{}\n{}", public_key, identity_key.unwrap_or(""))
+}
+
+fn verify_signature(identity_key: &str, message: &str, signature: &str) -> bool {
+    let key_bytes: [u8; 32] = match STANDARD.decode(identity_key).ok().and_then(|bytes| bytes.try_into().ok()) {
+        Some(bytes) => bytes,
+        None => return false,
+    };
+    let signature_bytes: [u8; 64] = match STANDARD.decode(signature).ok().and_then(|bytes| bytes.try_into().ok()) {
+        Some(bytes) => bytes,
+        None => return false,
+    };
+    match VerifyingKey::from_bytes(&key_bytes) {
+        Ok(verifying_key) => verifying_key.verify(message.as_bytes(), &Signature::from_bytes(&signature_bytes)).is_ok(),
+        Err(_) => false,
+    }
+}
+
+pub fn valid_identity_key(identity_key: &str) -> bool {
+    STANDARD
+        .decode(identity_key)
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .map(|bytes| VerifyingKey::from_bytes(&bytes).is_ok())
+        .unwrap_or(false)
+}
+
+// adds a device to `uid`'s keys and returns its id
+pub fn register_device(keys: &mut HashMap<UserIdentifier, UserKeys>, uid: &UserIdentifier, name: String, public_key: String, identity_key: Option<String>) -> u32 {
+    let user_keys = keys.entry(uid.clone()).or_default();
+    let mut device_id = rand::random::<u32>();
+    while user_keys.device(device_id).is_some() {
+        device_id = rand::random::<u32>();
+    }
+    user_keys.history.push(KeyChange {
+        device_id,
+        old_key: None,
+        new_key: Some(public_key.clone()),
+        signature: None,
+        timestamp: now_millis(),
+    });
+    user_keys.devices.push(DeviceKey {
+        device_id,
+        name,
+        public_key,
+        identity_key,
+        created: now_millis(),
+    });
+    device_id
+}
+
+// everyone sharing a chat with `uid` hears about their new keys, with the notice stored in each shared chat
+pub fn announce_key_change(uid: &UserIdentifier, device_id: u32, server: &Server) {
+    let fingerprint = match server.keys.lock().get(uid) {
+        Some(user_keys) => user_keys.fingerprint(),
+        None => return,
+    };
+    let shared_chats: Vec<(u32, Vec<UserIdentifier>)> = server
+        .chats
+        .entries()
+        .into_iter()
+        .filter_map(|(chatid, chat)| {
+            let chat = chat.lock();
+            chat.users.contains(uid).then(|| (chatid, chat.users.clone()))
+        })
+        .collect();
+    for (chatid, users) in shared_chats {
+        let sendable = key_changed(uid.username.clone(), device_id, fingerprint.clone(), chatid);
+        let sendable_id = rand::random::<u32>();
+        send_sendable(sendable.clone(), &users, server);
+        for user in &users {
+            server.user_db.with(user, |udb| udb.add_to_chat(chatid, sendable_id, DBEntry::sendable(sendable.clone())));
+        }
+    }
+}
+
+// the profile key is the first registered device's, older clients encrypt to it for every device sharing it
+fn sync_profile_key(uid: &UserIdentifier, server: &Server) {
+    let primary = server
+        .keys
+        .lock()
+        .get(uid)
+        .and_then(|user_keys| user_keys.devices.first().map(|device| device.public_key.clone()));
+    if let Some(user) = server.users.lock().get_mut(uid) {
+        user.public_key = primary.unwrap_or_default();
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AddDevice {
+    pub name: String,
+    pub public_key: String,
+    pub identity_key: Option<String>,
+    // once the account has a device, a new one is vouched for by one of them: `signed_by` is its
+    // device id and `signature` a base64 ed25519 signature over addition_message(public_key,
+    // identity_key) made with its identity key. devices without an identity key link instead
+    #[serde(default)]
+    pub signed_by: Option<u32>,
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RotateKey {
+    pub device_id: u32,
+    pub public_key: String,
+    // base64 ed25519 signature over rotation_message(device_id, current public key, public_key),
+    // made with the device's identity key
+    pub signature: String,
+}
+
+#[post("/keys/add-device/<token>", data = "<add_device>")]
+pub fn add_device(token: u32, add_device: Json<AddDevice>, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let uid = match server.tokens.lock().get(&token) {
+        Some(uid) => uid.clone(),
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    if add_device.identity_key.as_deref().map(|key| !valid_identity_key(key)).unwrap_or(false) {
+        return (ContentType::JSON, "{\"server\":\"invalid identity key\"}".into());
+    }
+    let device_id = {
+        let mut keys = server.keys.lock();
+        let has_devices = keys.get(&uid).map(|user_keys| !user_keys.devices.is_empty()).unwrap_or(false);
+        if has_devices {
+            let message = addition_message(&add_device.public_key, add_device.identity_key.as_deref());
+            let voucher = add_device.signed_by.and_then(|device_id| keys.get(&uid).and_then(|user_keys| user_keys.device(device_id)));
+            let verified = match (voucher.and_then(|device| device.identity_key.as_deref()), &add_device.signature) {
+                (Some(identity_key), Some(signature)) => verify_signature(identity_key, &message, signature),
+                _ => false,
+            };
+            if !verified {
+                return (ContentType::JSON, "{\"server\":\"invalid signature\"}".into());
+            }
+        }
+        register_device(&mut keys, &uid, add_device.name.clone(), add_device.public_key.clone(), add_device.identity_key.clone())
+    };
+    // a session that registers its first device becomes that device's session
+    if session_device(server, token).is_none() {
+        bind_session_device(server, token, device_id);
+    }
+    sync_profile_key(&uid, server);
+    announce_key_change(&uid, device_id, server);
+    (ContentType::JSON, format!("{{\"device_id\":{}}}", device_id))
+}
+
+#[post("/keys/rotate/<token>", data = "<rotate_key>")]
+pub fn rotate_key(token: u32, rotate_key: Json<RotateKey>, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let uid = match server.tokens.lock().get(&token) {
+        Some(uid) => uid.clone(),
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    {
+        let mut keys = server.keys.lock();
+        let user_keys = match keys.get_mut(&uid) {
+            Some(user_keys) => user_keys,
+            None => return (ContentType::JSON, "{\"server\":\"no such device\"}".into()),
+        };
+        let device = match user_keys.devices.iter_mut().find(|device| device.device_id == rotate_key.device_id) {
+            Some(device) => device,
+            None => return (ContentType::JSON, "{\"server\":\"no such device\"}".into()),
+        };
+        let message = rotation_message(rotate_key.device_id, &device.public_key, &rotate_key.public_key);
+        let verified = device
+            .identity_key
+            .as_deref()
+            .map(|identity_key| verify_signature(identity_key, &message, &rotate_key.signature))
+            .unwrap_or(false);
+        if !verified {
+            return (ContentType::JSON, "{\"server\":\"invalid signature\"}".into());
+        }
+        // a retired key may have been compromised, a device only ever moves to a fresh one
+        let used_before = rotate_key.public_key == device.public_key
+            || user_keys.history.iter().any(|change| {
+                change.device_id == rotate_key.device_id
+                    && (change.old_key.as_ref() == Some(&rotate_key.public_key) || change.new_key.as_ref() == Some(&rotate_key.public_key))
+            });
+        if used_before {
+            return (ContentType::JSON, "{\"server\":\"key already used\"}".into());
+        }
+        let old_key = std::mem::replace(&mut device.public_key, rotate_key.public_key.clone());
+        device.created = now_millis();
+        user_keys.history.push(KeyChange {
+            device_id: rotate_key.device_id,
+            old_key: Some(old_key),
+            new_key: Some(rotate_key.public_key.clone()),
+            signature: Some(rotate_key.signature.clone()),
+            timestamp: now_millis(),
+        });
+    }
+    sync_profile_key(&uid, server);
+    announce_key_change(&uid, rotate_key.device_id, server);
+    (ContentType::JSON, "{\"server\":\"rotated\"}".into())
+}
+
+#[post("/keys/remove-device/<token>/<device_id>")]
+pub fn remove_device(token: u32, device_id: u32, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let uid = match server.tokens.lock().get(&token) {
+        Some(uid) => uid.clone(),
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    {
+        let mut keys = server.keys.lock();
+        let user_keys = keys.entry(uid.clone()).or_default();
+        let position = match user_keys.devices.iter().position(|device| device.device_id == device_id) {
+            Some(position) => position,
+            None => return (ContentType::JSON, "{\"server\":\"no such device\"}".into()),
+        };
+        let removed = user_keys.devices.remove(position);
+        user_keys.history.push(KeyChange {
+            device_id,
+            old_key: Some(removed.public_key),
+            new_key: None,
+            signature: None,
+            timestamp: now_millis(),
+        });
+    }
+    // a removed device can't keep reading through the sessions it already had
+    let bound_tokens: Vec<u32> = server
+        .sessions
+        .lock()
+        .iter()
+        .filter(|(_, session)| session.device == Some(device_id))
+        .map(|(token, _)| *token)
+        .collect();
+    for bound_token in bound_tokens {
+        if server.tokens.lock().get(&bound_token) == Some(&uid) {
+            end_session(server, bound_token);
+        }
+    }
+    sync_profile_key(&uid, server);
+    announce_key_change(&uid, device_id, server);
+    (ContentType::JSON, "{\"server\":\"removed\"}".into())
+}
+
+#[derive(Serialize)]
+struct PublishedDevice<'a> {
+    #[serde(flatten)]
+    device: &'a DeviceKey,
+    fingerprint: String,
+}
+
+#[get("/keys/<username>")]
+pub fn get_keys(username: String, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let keys = server.keys.lock();
+    let user_keys = match keys.get(&UserIdentifier { username }) {
+        Some(user_keys) => user_keys,
+        None => return (ContentType::JSON, "{\"server\":\"no user\"}".into()),
+    };
+    let devices: Vec<PublishedDevice> = user_keys
+        .devices
+        .iter()
+        .map(|device| PublishedDevice { device, fingerprint: key_fingerprint(&device.public_key) })
+        .collect();
+    (ContentType::JSON, format!(
+        "{{\"devices\":{}, \"fingerprint\":\"{}\"}}",
+        serde_json::to_string(&devices).expect("couldn't serialize devices"),
+        user_keys.fingerprint()
+    ))
+}
+
+#[get("/keys/history/<username>")]
+pub fn get_key_history(username: String, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let keys = server.keys.lock();
+    match keys.get(&UserIdentifier { username }) {
+        Some(user_keys) => (ContentType::JSON, serde_json::to_string(&user_keys.history).expect("couldn't serialize key history")),
+        None => (ContentType::JSON, "{\"server\":\"no user\"}".into()),
+    }
+}
+
+#[get("/keys/safety-number/<token>/<username>")]
+pub fn get_safety_number(token: u32, username: String, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let uid = match server.tokens.lock().get(&token) {
+        Some(uid) => uid.clone(),
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    let them = UserIdentifier { username };
+    let (my_id, their_id) = match (server.user_id(&uid), server.user_id(&them)) {
+        (Some(my_id), Some(their_id)) => (my_id, their_id),
+        _ => return (ContentType::JSON, "{\"server\":\"no user\"}".into()),
+    };
+    let keys = server.keys.lock();
+    let empty = UserKeys::default();
+    let their_keys = match keys.get(&them) {
+        Some(their_keys) => their_keys,
+        None => return (ContentType::JSON, "{\"server\":\"no user\"}".into()),
+    };
+    let my_keys = keys.get(&uid).unwrap_or(&empty);
+    let number = safety_number((my_id, my_keys), (their_id, their_keys));
+    (ContentType::JSON, format!("{{\"safety_number\":\"{}\"}}", number))
+}
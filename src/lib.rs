@@ -0,0 +1,1423 @@
+#[macro_use]
+extern crate rocket;
+use rand::Rng;
+use rocket::fairing::{AdHoc, Fairing, Info, Kind};
+use rocket::form::Form;
+use rocket::fs::{FileServer, TempFile};
+use rocket::http::ContentType;
+use rocket::http::Header;
+use rocket::response::stream::TextStream;
+use rocket::serde::json::Json;
+use rocket::tokio::time::{self, Duration};
+use rocket::State;
+use rocket::{get, routes};
+use rocket::{Request, Response};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, create_dir, File, OpenOptions};
+use std::io::{Read, Write};
+use std::ops::DerefMut;
+use std::path::Path;
+use parking_lot::Mutex;
+use std::sync::{mpsc::*, Arc};
+use tracing::{debug, error, info};
+
+mod account;
+mod actions;
+mod admin;
+mod audit;
+pub mod cli;
+mod clock;
+mod contacts;
+mod directory;
+mod expiry;
+mod health;
+mod media;
+mod media_serving;
+mod keys;
+mod linking;
+mod logging;
+mod message;
+mod metrics;
+mod pfp;
+mod rate_limit;
+mod retention;
+mod scheduled;
+mod search;
+mod store;
+mod sendables;
+mod sessions;
+mod user;
+mod user_db;
+mod validation;
+mod warp_server;
+use account::*;
+use actions::*;
+use admin::*;
+use audit::*;
+use contacts::*;
+use directory::*;
+use expiry::*;
+use health::*;
+use media::*;
+use media_serving::*;
+use keys::*;
+use linking::*;
+use logging::*;
+use message::*;
+use metrics::*;
+use pfp::*;
+use rate_limit::*;
+use retention::*;
+use scheduled::*;
+use search::*;
+use store::*;
+use sendables::*;
+use sessions::*;
+use user::*;
+use user_db::*;
+use validation::*;
+
+// every store locks on its own and guards only live for a few statements, never across an await
+// (parking_lot guards aren't Send, so the compiler holds async handlers to that). chats, user dbs
+// and search indexes are sharded so work in one chat or for one user doesn't wait on another.
+// code that needs two stores at once takes them in field order and never goes back up the list
+pub struct Server {
+    // linking a device registers keys and opens a session while the pairing is held
+    pairings: Mutex<HashMap<u32, Pairing>>,
+    tokens: Mutex<HashMap<u32, UserIdentifier>>,
+    sessions: Mutex<HashMap<u32, Session>>,
+    passwords: Mutex<HashMap<UserIdentifier, String>>,
+    keys: Mutex<HashMap<UserIdentifier, UserKeys>>,
+    users: Mutex<HashMap<UserIdentifier, UserProfile>>,
+    // roles and suspensions, keyed by user id
+    accounts: Mutex<HashMap<u32, Account>>,
+    contacts: Mutex<HashMap<UserIdentifier, HashMap<u32, Contact>>>,
+    chats: Sharded<u32, Chat>,
+    // message_queue: Mutex<HashMap<UserIdentifier, HashMap<u32, Sendable>>>,
+    // sendable_queue: Mutex<HashMap<UserIdentifier, Vec<Sendable>>>,
+    chat_join_ids: Mutex<HashMap<u32, u32>>,
+    user_db: Sharded<UserIdentifier, UserDB>,
+    search_indexes: Sharded<UserIdentifier, SearchIndex>,
+    media: Mutex<MediaStore>,
+    event_stream_senders: Mutex<HashMap<UserIdentifier, Vec<EventSender>>>,
+    search_limiter: Mutex<SearchLimiter>,
+    // pending scheduled messages by id, only ever locked on its own
+    scheduled: Mutex<HashMap<u32, ScheduledMessage>>,
+    // lowercased names a rename moved away from, held until their history is rewritten. only ever
+    // locked last
+    renaming: Mutex<HashSet<String>>,
+    // atomics and its own locks, outside the lock order
+    metrics: Arc<Metrics>,
+    health: Health,
+    audit: AuditLog,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Self {
+            pairings: Mutex::new(HashMap::new()),
+            tokens: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            passwords: Mutex::new(HashMap::new()),
+            keys: Mutex::new(HashMap::new()),
+            users: Mutex::new(HashMap::new()),
+            accounts: Mutex::new(HashMap::new()),
+            contacts: Mutex::new(HashMap::new()),
+            chats: Sharded::new(),
+            // message_queue: Mutex::new(HashMap::new()),
+            // sendable_queue: Mutex::new(HashMap::new()),
+            chat_join_ids: Mutex::new(HashMap::new()),
+            user_db: Sharded::new(),
+            search_indexes: Sharded::new(),
+            media: Mutex::new(MediaStore::new()),
+            event_stream_senders: Mutex::new(HashMap::new()),
+            search_limiter: Mutex::new(SearchLimiter::default()),
+            scheduled: Mutex::new(HashMap::new()),
+            renaming: Mutex::new(HashSet::new()),
+            metrics: Arc::new(Metrics::default()),
+            health: Health::default(),
+            audit: AuditLog::new("save/audit.log"),
+        }
+    }
+
+    pub fn token_user(&self, token: u32) -> Option<UserIdentifier> {
+        self.tokens.lock().get(&token).cloned()
+    }
+
+    // the immutable id logs use, usernames can change and tokens are secrets
+    pub fn user_id(&self, uid: &UserIdentifier) -> Option<u32> {
+        self.users.lock().get(uid).map(|user| user.id)
+    }
+
+    pub fn from_file() -> Self {
+        if Path::new("save/chats.json").exists()
+            && Path::new("save/tokens.json").exists()
+            && Path::new("save/users.json").exists()
+        {
+            let users = Mutex::new(user::username_map_into(
+                serde_json::from_str(
+                    fs::read_to_string("save/users.json")
+                        .expect("Should have been able to read the file")
+                        .as_str(),
+                )
+                .expect("couldn't parse users"),
+            ));
+            // let message_queue = Mutex::new(user::username_map_into(
+            //     serde_json::from_str(
+            //         fs::read_to_string("save/message_queue.json")
+            //             .expect("Should have been able to read the file")
+            //             .as_str(),
+            //     )
+            //     .expect("couldn't parse message queue"),
+            // ));
+            // let sendable_queue = Mutex::new(user::username_map_into(
+            //     serde_json::from_str(
+            //         fs::read_to_string("save/sendable_queue.json")
+            //             .expect("Should have been able to read the file")
+            //             .as_str(),
+            //     )
+            //     .expect("couldn't parse sendable queue"),
+            // ));
+            let tokens = Mutex::new(
+                serde_json::from_str(
+                    fs::read_to_string("save/tokens.json")
+                        .expect("Should have been able to read the file")
+                        .as_str(),
+                )
+                .expect("couldn't parse tokens"),
+            );
+            let chats: HashMap<u32, Chat> = serde_json::from_str(
+                fs::read_to_string("save/chats.json")
+                    .expect("Should have been able to read the file")
+                    .as_str(),
+            )
+            .expect("couldn't parse chats");
+            let passwords = Mutex::new(user::username_map_into(
+                serde_json::from_str(
+                    fs::read_to_string("save/passwords.json")
+                        .expect("Should have been able to read the file")
+                        .as_str(),
+                )
+                .expect("couldn't parse passwords"),
+            ));
+            let chat_join_ids = Mutex::new(
+                serde_json::from_str(
+                    fs::read_to_string("save/chat_join_ids.json")
+                        .expect("Should have been able to read the file")
+                        .as_str(),
+                )
+                .expect("couldn't parse chat join ids"),
+            );
+            let mut user_db = user::username_map_into(serde_json::from_str(
+                fs::read_to_string("save/user_db.json")
+                    .expect("Should have been able to read the file")
+                    .as_str(),
+            )
+            .expect("couldn't parse user db"));
+            fill_missing_user_ids(&mut users.lock(), &mut user_db);
+            let mut media: MediaStore = read_optional_file("save/media.json");
+            let keys = Mutex::new(user::username_map_into(read_optional_file("save/keys.json")));
+            let contacts = Mutex::new(user::username_map_into(read_optional_file("save/contacts.json")));
+            let accounts = Mutex::new(read_optional_file("save/accounts.json"));
+            let scheduled = Mutex::new(read_optional_file("save/scheduled.json"));
+            let mut sessions = read_optional_file("save/sessions.json");
+            fill_missing_sessions(&tokens.lock(), &mut sessions);
+            let sessions = Mutex::new(sessions);
+            media.recount(&user_db);
+            let search_indexes = user_db
+                .iter()
+                .map(|(uid, udb)| (uid.clone(), SearchIndex::build(udb, &chats)))
+                .collect();
+            return Self {
+                pairings: Mutex::new(HashMap::new()),
+                tokens,
+                sessions,
+                passwords,
+                keys,
+                users,
+                accounts,
+                contacts,
+                chats: Sharded::from_map(chats),
+                // message_queue,
+                // sendable_queue,
+                chat_join_ids,
+                user_db: Sharded::from_map(user_db),
+                search_indexes: Sharded::from_map(search_indexes),
+                media: Mutex::new(media),
+                event_stream_senders: Mutex::new(HashMap::new()),
+                search_limiter: Mutex::new(SearchLimiter::default()),
+                scheduled,
+                renaming: Mutex::new(HashSet::new()),
+                metrics: Arc::new(Metrics::default()),
+                health: Health::default(),
+                audit: AuditLog::new("save/audit.log"),
+            };
+        }
+        return Self::new();
+    }
+}
+
+// save files added after the first release, missing ones just start out empty
+fn read_optional_file<T: serde::de::DeserializeOwned + Default>(path: &str) -> T {
+    if !Path::new(path).exists() {
+        return T::default();
+    }
+    serde_json::from_str(
+        fs::read_to_string(path)
+            .expect("Should have been able to read the file")
+            .as_str(),
+    )
+    .unwrap_or_else(|e| panic!("couldn't parse {path}: {e}"))
+}
+
+#[get("/events/<token>")]
+async fn events(token: u32, server_arc: &State<Arc<Server>>) -> TextStream![String + '_] {
+    let server: &Server = server_arc;
+    let (sender, receiver) = channel::<Sendable>();
+    let uid = server.token_user(token);
+    let mut invalid_token = false;
+    let stream_span = StreamSpan::open(server, token, "sse");
+    // let mut messages = Vec::new();
+    if uid.is_none() {
+        invalid_token = true;
+    } else {
+        add_event_sender(server, uid.as_ref().unwrap(), token, sender);
+        // if server.message_queue.lock().contains_key(uid.unwrap()) {
+        //     for message in server.message_queue.lock().get(uid.unwrap()).unwrap().values() {
+        //         messages.push(message.clone());
+        //     }
+        // }
+        // if server.sendable_queue.lock().contains_key(uid.unwrap()) {
+        //     for message in server.sendable_queue.lock().get(uid.unwrap()).unwrap() {
+        //         messages.push(message.clone());
+        //     }
+        // }
+        // server.message_queue.lock().insert(uid.unwrap().clone(), HashMap::new());
+        // server.sendable_queue.lock().insert(uid.unwrap().clone(), Vec::new());
+    }
+    return TextStream! {
+        let _stream_span = stream_span;
+        if invalid_token {
+            yield "{\"server_reponse\":\"invalid token\"}|endmessage|".to_string();
+        } else {
+            // for message in messages {
+            //     yield format!("{}|endmessage|", message.to_string());
+            // }
+            let mut interval = time::interval(Duration::from_secs(1));
+            let mut seconds = 31;
+            loop {
+                seconds += 1;
+                if seconds >= 30 {
+                    yield format!("{{\"server\":\"ping\"}}|endmessage|");
+                    seconds = 0;
+                }
+                match receiver.try_recv() {
+                    Ok(message) => yield format!("{}|endmessage|", message.to_string()),
+                    // the session was revoked or logged out, or the server is shutting down and
+                    // already sent a reconnect
+                    Err(TryRecvError::Disconnected) => {
+                        if !server.health.shutting_down() {
+                            yield "{\"server\":\"session ended\"}|endmessage|".to_string();
+                        }
+                        break;
+                    }
+                    // opened after the streams were closed for shutdown
+                    Err(TryRecvError::Empty) if server.health.shutting_down() => break,
+                    Err(TryRecvError::Empty) => {}
+                }
+                interval.tick().await;
+            }
+        }
+    };
+}
+
+#[post("/create-account/<username>/<password>", data = "<created_user>")]
+fn create_account(
+    username: String,
+    password: String,
+    created_user: Json<CreateUser>,
+    client: ClientInfo,
+    validation: &State<ValidationConfig>,
+    server_arc: &State<Arc<Server>>,
+) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let mut errors = FieldErrors::new();
+    errors.check("username", validate_username(&username, validation));
+    let name = errors.check("name", normalize_display_name(&created_user.name, validation));
+    let color = errors.check("color", parse_color(&created_user.color));
+    if created_user.identity_key.as_deref().map(|key| !valid_identity_key(key)).unwrap_or(false) {
+        errors.add("identity_key", "must be a base64 ed25519 public key");
+    }
+    if !errors.is_empty() {
+        return (ContentType::JSON, errors.to_json());
+    }
+    let uid = UserIdentifier {
+        username: username.clone(),
+    };
+    // checking and claiming the name under one lock, so two signups can't both get it
+    let user_id = {
+        let mut users = server.users.lock();
+        if username_taken(&username, &users, None) || server.renaming.lock().contains(&username.to_lowercase()) {
+            return (ContentType::JSON, "{\"server\":\"exists\"}".to_string());
+            /*if server
+                .passwords
+                .lock()
+                .get(&UserIdentifier {
+                    username: username.clone(),
+                })
+                .unwrap()
+                != &password
+            {
+                return (
+                    ContentType::JSON,
+                    "{\"server\":\"incorrect password\"}".to_string(),
+                );
+            }*/
+        }
+        let user_id = new_user_id(&users);
+        let mut user_profile = created_user.to_user_profile(user_id, username, "undefined".to_string());
+        user_profile.name = name.unwrap();
+        user_profile.color = color.unwrap();
+        users.insert(uid.clone(), user_profile);
+        user_id
+    };
+    server.passwords.lock().insert(uid.clone(), password);
+    let mut device = None;
+    if !created_user.public_key.is_empty() {
+        device = Some(register_device(
+            &mut server.keys.lock(),
+            &uid,
+            "first device".to_string(),
+            created_user.public_key.clone(),
+            created_user.identity_key.clone(),
+        ));
+    }
+    server.user_db.insert(uid.clone(), UserDB::new());
+    server.audit.record(AuditEntry::new(AuditAction::AccountCreated, user_id).user(user_id).ip(&client));
+    match create_session(server, uid, device, &client) {
+        Some(token) => (ContentType::JSON, format!("{{\"token\":{}}}", token)),
+        None => (ContentType::JSON, "{\"server\":\"user does not exist\"}".to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EditUser {
+    pub display_name: Option<String>,
+    pub color: Option<String>,
+    #[serde(default)]
+    pub discoverable: Option<bool>,
+}
+#[post("/edit-profile/<token>", data = "<edit_user>")]
+fn edit_profile(token: u32, edit_user: Json<EditUser>, validation: &State<ValidationConfig>, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    if let Some(uid) = server.token_user(token) {
+        let mut errors = FieldErrors::new();
+        let name = edit_user.display_name.as_ref().and_then(|name| errors.check("display_name", normalize_display_name(name, validation)));
+        let color = edit_user.color.as_ref().and_then(|color| errors.check("color", parse_color(color)));
+        if !errors.is_empty() {
+            return (ContentType::JSON, errors.to_json());
+        }
+        let mut users = server.users.lock();
+        let user_profile = match users.get_mut(&uid) {
+            Some(user_profile) => user_profile,
+            None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".to_string()),
+        };
+        if let Some(name) = name {
+            user_profile.name = name;
+        }
+        if let Some(color) = color {
+            user_profile.color = color;
+        }
+        if let Some(discoverable) = edit_user.discoverable {
+            user_profile.discoverable = discoverable;
+        }
+        return (ContentType::JSON, "{\"server\":\"updated\"}".to_string());
+    }
+    return (ContentType::JSON, "{\"server\":\"invalid token\"}".to_string());
+}
+
+#[get("/login/<username>/<password>?<device>")]
+fn login(
+    username: String,
+    password: String,
+    device: Option<u32>,
+    client: ClientInfo,
+    limiter: &State<Arc<RateLimiter>>,
+    server_arc: &State<Arc<Server>>,
+) -> Result<(ContentType, String), TooManyRequests> {
+    let ip = client.ip.as_deref().and_then(|ip| ip.parse().ok());
+    let server: &Server = server_arc;
+    let uid = UserIdentifier { username: username.clone() };
+    let user_id = server.user_id(&uid);
+    // the username is kept as the target since failures may be for accounts that don't exist
+    let failed = |detail: &str| AuditEntry::new(AuditAction::LoginFailed, 0).user(user_id.unwrap_or(0)).target(&username).ip(&client).detail(detail);
+    if let Err(retry_after) = limiter.login_locked(&username, ip) {
+        server.audit.record(failed("locked out"));
+        return Err(TooManyRequests(retry_after));
+    }
+    let user_id = match user_id {
+        Some(user_id) => user_id,
+        None => {
+            server.audit.record(failed("no such user"));
+            return Ok((ContentType::JSON, "{\"server\":\"user does not exist\"}".to_string()));
+        }
+    };
+    // an account that's still being created has no password yet
+    let correct = server.passwords.lock().get(&uid).map(|stored| *stored == password).unwrap_or(false);
+    if correct {
+        limiter.login_succeeded(&username, ip);
+        if is_suspended(server, &uid) {
+            server.audit.record(failed("suspended"));
+            return Ok((ContentType::JSON, "{\"server\":\"account suspended\"}".to_string()));
+        }
+        if let Some(device) = device {
+            let registered = server.keys.lock().get(&uid).map(|user_keys| user_keys.device(device).is_some()).unwrap_or(false);
+            if !registered {
+                return Ok((ContentType::JSON, "{\"server\":\"no such device\"}".to_string()));
+            }
+        }
+        server.audit.record(AuditEntry::new(AuditAction::Login, user_id).user(user_id).ip(&client));
+        return match create_session(server, uid, device, &client) {
+            Some(token) => Ok((ContentType::JSON, format!("{{\"token\":{}}}", token))),
+            // deleted since the password was checked
+            None => Ok((ContentType::JSON, "{\"server\":\"user does not exist\"}".to_string())),
+        };
+    } else {
+        limiter.login_failed(&username, ip);
+        server.audit.record(failed("incorrect password"));
+        return Ok((ContentType::JSON, "{\"server\":\"incorrect password\"}".to_string()));
+    }
+}
+
+#[post("/edit-chat/<chatid>/<token>", data = "<chat_edit>")]
+fn edit_chat(
+    chatid: u32,
+    token: u32,
+    chat_edit: Json<ChatEdit>,
+    client: ClientInfo,
+    request: RequestSpan,
+    server_arc: &State<Arc<Server>>,
+) {
+    let _entered = request.enter();
+    let server: &Server = server_arc;
+    let editor = match server.token_user(token) {
+        Some(editor) => editor,
+        None => {
+            debug!(chatid, "chat edit with an invalid token");
+            return;
+        }
+    };
+    let chat = match server.chats.get(&chatid) {
+        Some(chat) => chat,
+        None => {
+            debug!(chatid, "chat edit for a missing chat");
+            return;
+        }
+    };
+    if let Some(expire_after) = chat_edit.expire_after.filter(|expire_after| *expire_after != 0) {
+        if let Err(e) = validate_expire_after(expire_after) {
+            debug!(chatid, "chat edit with an invalid expiry: {e}");
+            return;
+        }
+    }
+    // blocks are looked up before the chat is locked, contacts come first in the lock order
+    let unblocked: Vec<UserIdentifier> = chat_edit
+        .added_users
+        .iter()
+        .filter(|added| !is_blocked(server, added, &editor))
+        .cloned()
+        .collect();
+    let (chat_users, added_users, reindex, admin_changed, expiry_changed) = {
+        let mut chat = chat.lock();
+        if chat.admin != editor {
+            debug!(chatid, "chat edit by someone who isn't the admin");
+            return;
+        }
+        let admin_changed = chat.admin != chat_edit.new_admin;
+        chat.admin = chat_edit.new_admin.clone();
+        chat.name = chat_edit.new_name.clone();
+        let added_users: Vec<UserIdentifier> = unblocked.into_iter().filter(|added| !chat.users.contains(added)).collect();
+        chat.users.append(&mut added_users.clone());
+        let reindex = match chat_edit.searchable {
+            Some(searchable) if searchable != chat.searchable => {
+                chat.searchable = searchable;
+                Some(searchable)
+            }
+            _ => None,
+        };
+        // 0 turns expiry off
+        let new_expiry = chat_edit.expire_after.map(|expire_after| if expire_after == 0 { None } else { Some(expire_after) });
+        let expiry_changed = match new_expiry {
+            Some(expire_after) if expire_after != chat.expire_after => {
+                chat.expire_after = expire_after;
+                Some(expire_after)
+            }
+            _ => None,
+        };
+        info!(chatid, added = added_users.len(), "chat updated");
+        (chat.users.clone(), added_users, reindex, admin_changed, expiry_changed)
+    };
+    if let Some(expire_after) = expiry_changed {
+        let name = server.users.lock().get(&editor).map(|profile| profile.name.clone()).unwrap_or_default();
+        let text = match expire_after {
+            Some(expire_after) => format!("{} set messages to disappear after {}", name, describe_duration(expire_after)),
+            None => format!("{} turned off disappearing messages", name),
+        };
+        post_banner(server, text, chatid, &chat_users);
+    }
+    if admin_changed {
+        let editor_id = server.user_id(&editor).unwrap_or(0);
+        let new_admin_id = server.user_id(&chat_edit.new_admin).unwrap_or(0);
+        server.audit.record(AuditEntry::new(AuditAction::ChatAdminChanged, editor_id).user(new_admin_id).target(chatid).ip(&client));
+    }
+    if let Some(searchable) = reindex {
+        // reindex history so turning search on covers older messages and turning it off drops their text
+        for user in &chat_users {
+            server.user_db.with(user, |udb| {
+                if let Some(entries) = udb.messages.get(&chatid) {
+                    server.search_indexes.with_or_insert(user, SearchIndex::default, |index| index.index_chat(entries, searchable));
+                }
+            });
+        }
+    }
+    for new_user in added_users {
+        let name = server
+            .users
+            .lock()
+            .get(&new_user)
+            .map(|profile| profile.name.clone())
+            .unwrap_or_default();
+        let mut rng = rand::thread_rng();
+        let banner_id = rng.gen::<u32>();
+        let sendable = banner(format!("{} joined this chat", name), chatid, banner_id.clone());
+        send_sendable(sendable.clone(), &chat_users, server);
+        for user in &chat_users {
+            server.user_db.with(user, |udb| udb.add_to_chat(chatid, banner_id, DBEntry::sendable(sendable.clone())));
+        }
+    }
+}
+
+#[get("/token-valid/<token>")]
+fn token_valid(token: u32, server_arc: &State<Arc<Server>>) -> String {
+    let server: &Server = server_arc;
+    if server.tokens.lock().contains_key(&token) {
+        return "true".to_string();
+    } else {
+        return "false".to_string();
+    }
+}
+
+#[get("/get-user/<username>")]
+fn get_user(username: String, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let uid = UserIdentifier { username };
+    if let Some(user) = server.users.lock().get(&uid) {
+        let user_json = serde_json::to_string(user)
+            .expect("Couldn't parse user");
+        return (ContentType::JSON, user_json);
+    }
+    return (ContentType::JSON, "{\"server\":\"no user\"}".to_string());
+}
+#[get("/get-chat/<chatid>/<token>")]
+fn get_chat(
+    chatid: u32,
+    token: u32,
+    server_arc: &State<Arc<Server>>,
+) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let chat = match server.chats.get(&chatid) {
+        Some(chat) => chat,
+        None => return (ContentType::JSON, "{\"server\":\"no chat\"}".to_string()),
+    };
+    let uid = match server.token_user(token) {
+        Some(uid) => uid,
+        None => {
+            return (
+                ContentType::JSON,
+                "{\"server\":\"invalid token\"}".to_string(),
+            )
+        }
+    };
+    let chat = chat.lock();
+    if chat.users.contains(&uid) {
+        let chat_json = serde_json::to_string(&*chat).expect("Couldn't parse chat");
+        return (ContentType::JSON, chat_json);
+    } else {
+        return (
+            ContentType::JSON,
+            "{\"server\":\"user not in chat\"}".to_string(),
+        );
+    }
+}
+
+#[get("/create-chat-link/<chatid>/<token>")]
+fn create_chat_link(
+    chatid: u32,
+    token: u32,
+    _limit: UserRateLimit,
+    client: ClientInfo,
+    server_arc: &State<Arc<Server>>,
+) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let chat_users = match server.chats.with(&chatid, |chat| chat.users.clone()) {
+        Some(chat_users) => chat_users,
+        None => return (ContentType::JSON, "{\"server\":\"no chat\"}".to_string()),
+    };
+    let uid = match server.token_user(token) {
+        Some(uid) => uid,
+        None => {
+            return (
+                ContentType::JSON,
+                "{\"server\":\"invalid token\"}".to_string(),
+            )
+        }
+    };
+    if chat_users.contains(&uid) {
+        let mut rng = rand::thread_rng();
+        let join_code = rng.gen::<u32>();
+        server
+            .chat_join_ids
+            .lock()
+            .insert(join_code, chatid);
+        let user_id = server.user_id(&uid).unwrap_or(0);
+        server.audit.record(AuditEntry::new(AuditAction::InviteCreated, user_id).user(user_id).target(chatid).ip(&client).detail(join_code));
+        return (
+            ContentType::JSON,
+            format!("{{\"join_code\":{}}}", join_code),
+        );
+    } else {
+        return (
+            ContentType::JSON,
+            "{\"server\":\"user not in chat\"}".to_string(),
+        );
+    }
+}
+
+#[post("/join-chat-link/<join_code>/<token>")]
+fn join_chat_link(join_code: u32, token: u32, _limit: UserRateLimit, client: ClientInfo, request: RequestSpan, server_arc: &State<Arc<Server>>) {
+    let _entered = request.enter();
+    let server: &Server = server_arc;
+    let chatid = match server.chat_join_ids.lock().get(&join_code) {
+        Some(chatid) => *chatid,
+        None => return,
+    };
+    let uid = match server.token_user(token) {
+        Some(uid) => uid,
+        None => return,
+    };
+    let members = match server.chats.with(&chatid, |chat| chat.users.clone()) {
+        Some(members) => members,
+        None => return,
+    };
+    if members.contains(&uid) {
+        return;
+    }
+    if members.iter().any(|member| is_blocked(server, member, &uid)) {
+        info!(chatid, "join refused, a member blocked the user");
+        return;
+    }
+    // someone may have added them since the members were copied
+    let joined = server.chats.with(&chatid, |chat| {
+        if chat.users.contains(&uid) {
+            return None;
+        }
+        chat.users.push(uid.clone());
+        info!(chatid, "user joined chat by link");
+        Some(chat.users.clone())
+    });
+    let chat_users = match joined.flatten() {
+        Some(chat_users) => chat_users,
+        None => return,
+    };
+    let user_id = server.user_id(&uid).unwrap_or(0);
+    server.audit.record(AuditEntry::new(AuditAction::InviteUsed, user_id).user(user_id).target(chatid).ip(&client).detail(join_code));
+    let name = server
+        .users
+        .lock()
+        .get(&uid)
+        .map(|profile| profile.name.clone())
+        .unwrap_or_default();
+    let mut rng = rand::thread_rng();
+    let banner_id = rng.gen::<u32>();
+    let sendable = banner(format!("{} joined this chat", name), chatid, banner_id.clone());
+    send_sendable(sendable.clone(), &chat_users, server);
+    for user in &chat_users {
+        server.user_db.with(user, |udb| udb.add_to_chat(chatid, banner_id, DBEntry::sendable(sendable.clone())));
+    }
+}
+
+#[post("/received-message/<token>/<chatid>/<messageid>/<to_user>")]
+fn received_message(
+    token: u32,
+    chatid: u32,
+    messageid: u32,
+    to_user: String,
+    server_arc: &State<Arc<Server>>,
+) {
+    let server: &Server = server_arc;
+    if let Some(reader) = server.token_user(token) {
+        // server
+        //     .message_queue
+        //     .lock()
+        //     .get_mut(uid.unwrap())
+        //     .unwrap()
+        //     .remove(&messageid);
+        let sender_uid = UserIdentifier { username: to_user };
+        let sendable = read(
+            "Delivered".to_string(),
+            reader.username.clone(),
+            messageid,
+            chatid,
+        );
+        send_sendable_from(sendable, &reader, &[sender_uid.clone()], server);
+        server.user_db.with(&sender_uid, |udb| udb.edit_message(chatid, messageid, |message| message.read = "Delivered".into()));
+    }
+}
+
+#[derive(FromForm)]
+struct PfpImage<'f> {
+    pfp_image: TempFile<'f>,
+}
+
+#[post(
+    "/change-pfp/<token>",
+    format = "multipart/form-data",
+    data = "<pfp_form>"
+)]
+async fn change_pfp(token: u32, mut pfp_form: Form<PfpImage<'_>>, config: &State<MediaConfig>, client: ClientInfo, request: RequestSpan, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let uid = server_arc.tokens.lock().get(&token).cloned();
+    let uid = match uid {
+        Some(uid) => uid,
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".to_string()),
+    };
+    let user_id = server_arc.user_id(&uid).unwrap_or(0);
+    let upload_path = Path::new(&config.media_root).join("staging").join(format!("pfp-{}", rand::random::<u64>()));
+    if fs::create_dir_all(upload_path.parent().unwrap()).is_err() || !save_pfp(&mut pfp_form.pfp_image, &upload_path, &request).await {
+        return (ContentType::JSON, "{\"server\":\"couldn't save pfp\"}".to_string());
+    }
+    let config_copy = config.inner().clone();
+    let processed = rocket::tokio::task::spawn_blocking(move || {
+        let processed = process_pfp(&upload_path, user_id, &config_copy);
+        let _ = fs::remove_file(&upload_path);
+        processed
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.to_string()));
+    let name = match processed {
+        Ok(name) => name,
+        Err(e) => {
+            info!(parent: &request.span, "rejected pfp: {e}");
+            return (ContentType::JSON, "{\"server\":\"invalid image\"}".to_string());
+        }
+    };
+    let url = pfp_url(config, &name);
+    let server: &Server = server_arc;
+    let old_pfp = server.users.lock().get_mut(&uid).map(|user| std::mem::replace(&mut user.pfp, url.clone()));
+    if let Some(old_pfp) = old_pfp {
+        remove_pfp_files(config, &old_pfp);
+        info!(parent: &request.span, "pfp changed");
+        let user_id = server.user_id(&uid).unwrap_or(0);
+        server.audit.record(AuditEntry::new(AuditAction::PfpChanged, user_id).user(user_id).ip(&client));
+    }
+    (ContentType::JSON, format!("{{\"pfp\":{}}}", serde_json::to_string(&url).unwrap()))
+}
+
+#[post("/delete-pfp/<token>")]
+fn delete_pfp(token: u32, config: &State<MediaConfig>, client: ClientInfo, request: RequestSpan, server_arc: &State<Arc<Server>>) {
+    let _entered = request.enter();
+    let server: &Server = server_arc;
+    if let Some(uid) = server.token_user(token) {
+        let old_pfp = server
+            .users
+            .lock()
+            .get_mut(&uid)
+            .map(|user| std::mem::replace(&mut user.pfp, "undefined".to_string()));
+        if let Some(old_pfp) = old_pfp {
+            info!("pfp deleted");
+            remove_pfp_files(config, &old_pfp);
+            let user_id = server.user_id(&uid).unwrap_or(0);
+            server.audit.record(AuditEntry::new(AuditAction::PfpDeleted, user_id).user(user_id).ip(&client));
+        }
+    }
+}
+
+async fn save_pfp<'f>(pfp: &mut TempFile<'f>, new_path: &Path, request: &RequestSpan) -> bool {
+    match pfp.move_copy_to(new_path).await {
+        Ok(()) => true,
+        Err(e) => {
+            error!(parent: &request.span, "couldn't save pfp to {}: {e}", new_path.display());
+            false
+        }
+    }
+}
+
+#[post("/read-message/<token>/<chatid>/<messageid>/<to_user>")]
+fn read_message(
+    token: u32,
+    chatid: u32,
+    messageid: u32,
+    to_user: String,
+    server_arc: &State<Arc<Server>>,
+) {
+    let server: &Server = server_arc;
+    if let Some(reader) = server.token_user(token) {
+        let sender_uid = UserIdentifier { username: to_user };
+        let sendable = read(
+            "Read".to_string(),
+            reader.username.clone(),
+            messageid,
+            chatid,
+        );
+        send_sendable_from(sendable, &reader, &[sender_uid.clone()], server);
+        server.user_db.with(&sender_uid, |udb| udb.edit_message(chatid, messageid, |message| message.read = "Read".into()));
+    }
+}
+
+#[post("/read-chat/<token>/<chatid>/<messageid>")]
+fn read_chat(
+    token: u32,
+    chatid: u32,
+    messageid: u32,
+    server_arc: &State<Arc<Server>>,
+) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let uid = server.tokens.lock().get(&token).cloned();
+    match uid {
+        Some(uid) => {
+            let marked = read_up_to(&uid, chatid, messageid, server);
+            (ContentType::JSON, format!("{{\"marked\":{}}}", marked))
+        }
+        None => (ContentType::JSON, "{\"server\":\"invalid token\"}".to_string()),
+    }
+}
+
+#[post("/logout/<token>")]
+fn logout(token: u32, client: ClientInfo, server_arc: &State<Arc<Server>>) {
+    let server: &Server = server_arc;
+    let session = server.sessions.lock().get(&token).map(|session| session.id);
+    if let Some(user_id) = server.token_user(token).and_then(|uid| server.user_id(&uid)) {
+        let entry = AuditEntry::new(AuditAction::Logout, user_id).user(user_id).ip(&client);
+        server.audit.record(match session {
+            Some(session) => entry.target(session),
+            None => entry,
+        });
+    }
+    end_session(server, token);
+}
+
+#[post("/post-message/<token>", data = "<encrypted_messages>")]
+fn post_message(
+    token: u32,
+    encrypted_messages: Json<EncryptedMessages>,
+    _limit: UserRateLimit,
+    request: RequestSpan,
+    server_arc: &State<Arc<Server>>,
+) -> (ContentType, String) {
+    let _entered = request.enter();
+    let server: &Server = server_arc;
+    let sender = match server.tokens.lock().get(&token) {
+        Some(uid) => uid.clone(),
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".to_string()),
+    };
+    match deliver_messages(server, &sender, &encrypted_messages, None) {
+        Ok(message_id) => (ContentType::JSON, format!("{{\"id\":{}}}", message_id)),
+        Err(body) => (ContentType::JSON, body),
+    }
+}
+
+// fans one message out to each recipient's copy, sent by `sender`. `timestamp` replaces the
+// clients' timestamps, scheduled messages are stamped when they go out. returns the new message id,
+// or the error body when nobody got it
+fn deliver_messages(server: &Server, sender: &UserIdentifier, encrypted_messages: &EncryptedMessages, timestamp: Option<u128>) -> Result<u32, String> {
+    if let Some(body) = missing_devices_error(encrypted_messages, server) {
+        return Err(body);
+    }
+    let recipients = encrypted_messages.recipients();
+    let others: Vec<&String> = recipients.iter().filter(|to_user| **to_user != sender.username).collect();
+    let blocked_by = others
+        .iter()
+        .filter(|to_user| is_blocked(server, &UserIdentifier { username: to_user.to_string() }, sender))
+        .count();
+    // the same answer whether they blocked you or not, so blocking can't be probed for
+    if !others.is_empty() && blocked_by == others.len() {
+        return Err("{\"server\":\"message not delivered\"}".to_string());
+    }
+    let from_id = server.user_id(sender).unwrap_or(0);
+    let mut rng = rand::thread_rng();
+    let message_id = rng.gen::<u32>();
+    for to_user in recipients {
+        let shared_copy = encrypted_messages.encrypted_messages.get(&to_user);
+        let device_copies = encrypted_messages.device_messages.get(&to_user);
+        let sent_message = match shared_copy.or_else(|| device_copies.and_then(|copies| copies.values().next())) {
+            Some(sent_message) => sent_message,
+            None => continue,
+        };
+        let mut message = sent_message.to_message(message_id, sender.clone(), from_id);
+        if let Some(timestamp) = timestamp {
+            message.timestamp = timestamp;
+        }
+        if shared_copy.is_none() {
+            message.text = String::new();
+            message.attachments = Vec::new();
+        }
+        message.attachments = server.media.lock().verify_attachments(&message.attachments);
+        for (device, device_message) in device_copies.into_iter().flatten() {
+            let copy = DeviceCopy {
+                text: device_message.text.clone(),
+                attachments: server.media.lock().verify_attachments(&device_message.attachments),
+            };
+            message.device_copies.insert(*device, copy);
+        }
+        send_message(
+            message,
+            UserIdentifier {
+                username: to_user.clone(),
+            },
+            server,
+        );
+    }
+    Ok(message_id)
+}
+
+#[post("/react-message/<token>/<chatid>/<messageid>/<emoji>")]
+fn react_message(
+    token: u32,
+    chatid: u32,
+    messageid: u32,
+    emoji: String,
+    _limit: UserRateLimit,
+    server_arc: &State<Arc<Server>>,
+) -> String {
+    let server: &Server = server_arc;
+    let user = match server.token_user(token) {
+        Some(user) => user,
+        None => return "Invalid Token >:(".to_string(),
+    };
+    if let Some(chat_users) = server.chats.with(&chatid, |chat| chat.users.clone()) {
+        if chat_users.contains(&user) {
+            let reaction = reaction(emoji.clone(), user.username.clone(), messageid, chatid);
+            send_sendable_from(reaction, &user, &chat_users, server);
+            for to_user in &chat_users {
+                if is_blocked(server, to_user, &user) {
+                    continue;
+                }
+                server.user_db.with(to_user, |udb| {
+                    udb.edit_message(chatid, messageid, |message| {
+                        message.reactions.insert(user.username.clone(), emoji.clone());
+                    })
+                });
+            }
+        }
+    }
+    "Thank you :)".to_string()
+}
+
+#[post("/create-chat/<token>", data = "<created_chat>")]
+fn create_chat(
+    token: u32,
+    created_chat: Json<CreateChat>,
+    _limit: UserRateLimit,
+    server_arc: &State<Arc<Server>>,
+) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let creator = match server.token_user(token) {
+        Some(uid) => uid,
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".to_string()),
+    };
+    if let Some(expire_after) = created_chat.expire_after {
+        let mut errors = FieldErrors::new();
+        errors.check("expire_after", validate_expire_after(expire_after));
+        if !errors.is_empty() {
+            return (ContentType::JSON, errors.to_json());
+        }
+    }
+    let mut rng = rand::thread_rng();
+    let mut id = rng.gen::<u32>();
+    loop {
+        if !server.chats.contains_key(&id) {
+            break;
+        }
+        id = rng.gen::<u32>();
+    }
+    let mut chat = created_chat.to_chat(id, creator);
+    // people who blocked the creator just don't end up in the chat
+    chat.users.retain(|user| !is_blocked(server, user, &chat.admin));
+    let chat_json = serde_json::to_string(&chat).expect("Couldn't Serialize Message!");
+    server.chats.insert(id, chat);
+    return (ContentType::JSON, chat_json);
+}
+
+fn write_to_file(server: &Server) -> std::io::Result<()> {
+    info!("writing to files");
+    let started = std::time::Instant::now();
+    let result = write_files(server);
+    server.metrics.record_persist(started.elapsed(), result.as_ref().ok().map(|_| save_size("save")));
+    result
+}
+
+fn write_files(server: &Server) -> std::io::Result<()> {
+    if !Path::new("save").exists() {
+        create_dir("save")?;
+    }
+    let mut users_file = create_or_open_file("save/users.json")?;
+    users_file.write_all(
+        serde_json::to_string(&user::uid_map_into(server.users.lock().clone()))
+            .expect("could not write to users file")
+            .as_bytes(),
+    )?;
+    // let mut message_queue_file = create_or_open_file("save/message_queue.json")?;
+    // message_queue_file.write_all(
+    //     serde_json::to_string(&user::uid_map_into(
+    //         server.message_queue.lock().clone(),
+    //     ))
+    //     .expect("could not write to message queue file")
+    //     .as_bytes(),
+    // )?;
+    // let mut sendable_queue_file = create_or_open_file("save/sendable_queue.json")?;
+    // sendable_queue_file.write_all(
+    //     serde_json::to_string(&user::uid_map_into(
+    //         server.sendable_queue.lock().clone(),
+    //     ))
+    //     .expect("could not write to sendable queue file")
+    //     .as_bytes(),
+    // )?;
+    let mut tokens_file = create_or_open_file("save/tokens.json")?;
+    tokens_file.write_all(
+        serde_json::to_string(server.tokens.lock().deref_mut())
+            .expect("could not write to tokens file")
+            .as_bytes(),
+    )?;
+    let mut sessions_file = create_or_open_file("save/sessions.json")?;
+    sessions_file.write_all(
+        serde_json::to_string(server.sessions.lock().deref_mut())
+            .expect("could not write to sessions file")
+            .as_bytes(),
+    )?;
+    let mut chats_file = create_or_open_file("save/chats.json")?;
+    chats_file.write_all(
+        serde_json::to_string(&server.chats.snapshot())
+            .expect("could not write to chats file")
+            .as_bytes(),
+    )?;
+    let mut passwords_file = create_or_open_file("save/passwords.json")?;
+    passwords_file.write_all(
+        serde_json::to_string(&user::uid_map_into(
+            server.passwords.lock().clone(),
+        ))
+        .expect("could not write to passwords file")
+        .as_bytes(),
+    )?;
+    let mut chat_join_ids_file = create_or_open_file("save/chat_join_ids.json")?;
+    chat_join_ids_file.write_all(
+        serde_json::to_string(&server.chat_join_ids.lock().clone())
+            .expect("could not write to chat join ids file")
+            .as_bytes(),
+    )?;
+    let mut media_file = create_or_open_file("save/media.json")?;
+    media_file.write_all(
+        serde_json::to_string(server.media.lock().deref_mut())
+            .expect("could not write to media file")
+            .as_bytes(),
+    )?;
+    let mut keys_file = create_or_open_file("save/keys.json")?;
+    keys_file.write_all(
+        serde_json::to_string(&user::uid_map_into(server.keys.lock().clone()))
+            .expect("could not write to keys file")
+            .as_bytes(),
+    )?;
+    let mut accounts_file = create_or_open_file("save/accounts.json")?;
+    accounts_file.write_all(
+        serde_json::to_string(server.accounts.lock().deref_mut())
+            .expect("could not write to accounts file")
+            .as_bytes(),
+    )?;
+    let mut contacts_file = create_or_open_file("save/contacts.json")?;
+    contacts_file.write_all(
+        serde_json::to_string(&user::uid_map_into(server.contacts.lock().clone()))
+            .expect("could not write to contacts file")
+            .as_bytes(),
+    )?;
+    let mut scheduled_file = create_or_open_file("save/scheduled.json")?;
+    scheduled_file.write_all(
+        serde_json::to_string(server.scheduled.lock().deref_mut())
+            .expect("could not write to scheduled file")
+            .as_bytes(),
+    )?;
+    let mut user_db_file = create_or_open_file("save/user_db.json")?;
+    user_db_file.write_all(
+        serde_json::to_string(&user::uid_map_into(server.user_db.snapshot()))
+            .expect("could not write to user_db file")
+            .as_bytes(),
+    )?;
+    Ok(())
+}
+
+fn create_or_open_file(path: &str) -> Result<std::fs::File, std::io::Error> {
+    return OpenOptions::new()
+        .write(true)
+        .create(!Path::new(path).exists())
+        .truncate(true)
+        .open(path);
+}
+
+#[get("/?<joinchat>")]
+fn join_headers(joinchat: u32, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let mut file = File::open("..\\messenger-client\\build\\index.html")
+        .expect("index.html does not exist!!!");
+    let mut file_text = String::new();
+    file.read_to_string(&mut file_text)
+        .expect("could not read from index.html!!!");
+    let chatid_option = server.chat_join_ids.lock().get(&joinchat).copied();
+    let chat_option = chatid_option.and_then(|chatid| server.chats.with(&chatid, |chat| (chat.name.clone(), chat.admin.clone())));
+    if let Some((chat_name, admin)) = chat_option {
+        let admin_name = server.users.lock().get(&admin).map(|user| user.name.clone());
+        if let Some(admin_name) = admin_name {
+            file_text = file_text.replace("<meta name=\"description\" content=\"Christopher's Cool Messaging app\"/>", format!("<meta name=\"description\" content=\"You are invited to join {}'s chat: {}\nClick here to join\"/>", admin_name, chat_name).as_str());
+        }
+    }
+    return (ContentType::HTML, file_text);
+}
+
+#[options("/<_..>")]
+fn all_options() {
+    /* Intentionally left empty */
+}
+
+// the server itself, the `messaging_server` binary only calls this
+pub async fn run() {
+    // before rocket::build, which installs rocket's own logger otherwise
+    let log_config: LogConfig = rocket::Config::figment().extract().unwrap_or_default();
+    init_logging(&log_config);
+    let rocket = rocket::build();
+    let server = Arc::new(Server::from_file());
+    let audit_config: AuditConfig = rocket.figment().extract().unwrap_or_default();
+    server.audit.configure(&audit_config);
+    let admin_config: AdminConfig = rocket.figment().extract().unwrap_or_default();
+    promote_admins(&server, &admin_config);
+    let rate_limit_config: RateLimitConfig = rocket.figment().extract().unwrap_or_default();
+    let limiter = Arc::new(RateLimiter::new(rate_limit_config, Arc::new(clock::SystemClock)));
+    let warp_limiter = limiter.clone();
+    let gc_server = server.clone();
+    let expiry_server = server.clone();
+    let retention_server = server.clone();
+    let schedule_config: ScheduleConfig = rocket.figment().extract().unwrap_or_default();
+    let scheduler = Arc::new(Scheduler::new(schedule_config, Arc::new(clock::SystemClock)));
+    let schedule_server = server.clone();
+    let due_scheduler = scheduler.clone();
+    let ignited = rocket
+        .attach(RequestTracing)
+        .attach(HttpMetrics)
+        .attach(CORS)
+        .attach(RateLimitFairing(limiter.clone()))
+        .attach(SessionActivity)
+        .attach(AdHoc::config::<MediaConfig>())
+        .attach(AdHoc::config::<ValidationConfig>())
+        .attach(AdHoc::config::<MetricsConfig>())
+        .attach(AdHoc::config::<ShutdownConfig>())
+        .attach(AdHoc::on_liftoff("Attachment GC", |rocket| Box::pin(async move {
+            let config = rocket.state::<MediaConfig>().unwrap().clone();
+            rocket::tokio::spawn(async move {
+                let mut interval = time::interval(Duration::from_secs(60 * 60));
+                loop {
+                    interval.tick().await;
+                    collect_garbage(&gc_server, &config);
+                }
+            });
+        })))
+        .attach(AdHoc::on_liftoff("Message expiry", |rocket| Box::pin(async move {
+            let config: ExpiryConfig = rocket.figment().extract().unwrap_or_default();
+            rocket::tokio::spawn(async move {
+                let mut interval = time::interval(Duration::from_secs(config.expiry_sweep_interval.max(1)));
+                loop {
+                    interval.tick().await;
+                    sweep_expired(&expiry_server);
+                }
+            });
+        })))
+        .attach(AdHoc::on_liftoff("History retention", |rocket| Box::pin(async move {
+            let config: RetentionConfig = rocket.figment().extract().unwrap_or_default();
+            if !config.enabled() {
+                return;
+            }
+            rocket::tokio::spawn(async move {
+                let mut interval = time::interval(Duration::from_secs(config.retention_interval.max(1)));
+                loop {
+                    interval.tick().await;
+                    compact_history(&retention_server, &config);
+                }
+            });
+        })))
+        .attach(AdHoc::on_liftoff("Scheduled messages", |_| Box::pin(async move {
+            // the first tick is immediate, so anything that came due while the server was down goes out now
+            rocket::tokio::spawn(async move {
+                let mut interval = time::interval(due_scheduler.check_interval());
+                loop {
+                    interval.tick().await;
+                    due_scheduler.run_due(&schedule_server);
+                }
+            });
+        })))
+        .attach(AdHoc::on_liftoff("Readiness", |rocket| Box::pin(async move {
+            if let Some(server_arc) = rocket.state::<Arc<Server>>() {
+                server_arc.health.set_rocket_listening(true);
+            }
+        })))
+        .attach(AdHoc::on_shutdown("Close event streams", |rocket| Box::pin(async move {
+            let reconnect_after = rocket.state::<ShutdownConfig>().map(|config| config.reconnect_after).unwrap_or_default();
+            if let Some(server_arc) = rocket.state::<Arc<Server>>() {
+                server_arc.health.set_rocket_listening(false);
+                close_event_streams(server_arc, reconnect_after);
+            }
+        })))
+        .manage(server.clone())
+        .manage(limiter)
+        .manage(scheduler)
+        .register("/", catchers![too_many_requests, payload_too_large])
+        .mount(
+            "/",
+            FileServer::from("..\\messenger-client\\build"),
+        )
+        .mount(
+            "/",
+            routes![
+                join_headers,
+                events,
+                post_message,
+                get_user,
+                create_account,
+                logout,
+                create_chat,
+                // create_user,
+                token_valid,
+                all_options,
+                get_chat,
+                received_message,
+                edit_chat,
+                read_message,
+                read_chat,
+                create_chat_link,
+                join_chat_link,
+                change_pfp,
+                get_pfp,
+                delete_pfp,
+                react_message,
+                create_link,
+                claim_link,
+                send_link_message,
+                receive_link_messages,
+                approve_link,
+                list_sessions,
+                rename_session,
+                revoke_session,
+                revoke_other_sessions,
+                change_password,
+                change_username,
+                delete_account,
+                search_users,
+                get_contacts,
+                add_contact,
+                label_contact,
+                block_contact,
+                unblock_contact,
+                remove_contact,
+                get_blocked,
+                block_user,
+                unblock_user,
+                rate_limited,
+                login,
+                get_message,
+                get_chat_messages,
+                get_chats,
+                edit_profile,
+                search_messages,
+                upload_attachment,
+                upload_encrypted_attachment,
+                get_attachment,
+                add_device,
+                rotate_key,
+                remove_device,
+                get_keys,
+                get_key_history,
+                get_safety_number,
+                get_metrics,
+                healthz,
+                readyz,
+                admin_list_users,
+                admin_list_sessions,
+                admin_close_session,
+                admin_close_all_sessions,
+                admin_suspend,
+                admin_unsuspend,
+                admin_list_chats,
+                admin_delete_chat,
+                admin_broadcast,
+                admin_stats,
+                admin_audit,
+                get_audit_events,
+                schedule_message,
+                get_scheduled_messages,
+                edit_scheduled_message,
+                cancel_scheduled_message,
+            ],
+        )
+        .ignite()
+        .await;
+    let rocket = match ignited {
+        Ok(rocket) => rocket,
+        Err(e) => {
+            error!("rocket failed to start: {e}");
+            return;
+        }
+    };
+    let shutdown_config = rocket.state::<ShutdownConfig>().cloned().unwrap_or_default();
+    // one shutdown for both servers, rocket triggers it on ctrl-c or SIGTERM
+    let shutdown = rocket.shutdown();
+    let warp = rocket::tokio::spawn(warp_server::warp_start(server.clone(), warp_limiter, shutdown.clone()));
+    let result = rocket.launch().await;
+    match result {
+        Ok(_val) => {}
+        Err(e) => error!("rocket failed: {e}"),
+    }
+    // rocket may have stopped without a shutdown request, e.g. when it couldn't bind
+    shutdown.notify();
+    if !server.health.shutting_down() {
+        close_event_streams(&server, shutdown_config.reconnect_after);
+    }
+    drain_websockets(&server, warp, Duration::from_secs(shutdown_config.shutdown_deadline)).await;
+    write_to_file(&server).expect("Failed to write server data!");
+    info!("shutdown complete");
+}
+
+pub struct CORS;
+
+#[rocket::async_trait]
+impl Fairing for CORS {
+    fn info(&self) -> Info {
+        Info {
+            name: "Add CORS headers to responses",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if let Some(hostname) = request.headers().get_one("origin") {
+            if hostname == "http://minecraft.themagicdoor.org:8000"
+                || hostname == "http://minecraft.themagicdoor.org:3000"
+                || hostname == "http://localhost:3000"
+                || hostname.contains("http://localhost:")
+            {
+                response.set_header(Header::new("Access-Control-Allow-Origin", hostname));
+            }
+        } else {
+            response.set_header(Header::new("Access-Control-Allow-Origin", "nothing lmao"));
+        }
+        response.set_header(Header::new(
+            "Access-Control-Allow-Methods",
+            "POST, GET, PATCH, OPTIONS",
+        ));
+        response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
+        response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+    }
+}
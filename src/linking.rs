@@ -0,0 +1,290 @@
+use std::{collections::HashMap, sync::Arc};
+
+use rand::Rng;
+use rocket::{State, http::ContentType, response::stream::TextStream, serde::json::Json, tokio::time::{self, Duration}};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{Server, keys::{announce_key_change, register_device, valid_identity_key}, media::now_millis, rate_limit::UserRateLimit, sessions::{create_session, end_session, ClientInfo}, user::UserIdentifier};
+
+const PAIRING_TIMEOUT_MILLIS: u128 = 5 * 60 * 1000;
+const PAIRING_CODE_LIMIT: u32 = 100_000_000;
+
+#[derive(Deserialize, Clone)]
+pub struct NewDevice {
+    pub name: String,
+    pub public_key: String,
+    pub identity_key: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Existing,
+    New,
+}
+
+// a short lived, single use link between a logged in device and one that wants to join the account.
+// the existing side authenticates with its session token, the new side with the secret it got when claiming
+pub struct Pairing {
+    pub uid: UserIdentifier,
+    expires: u128,
+    claim_secret: Option<u32>,
+    new_device: Option<NewDevice>,
+    // set once the new device has its session, nothing else can be done with the code after that
+    approved: bool,
+    // that session's token, until the new device's stream has been sent it
+    new_token: Option<u32>,
+    new_client: ClientInfo,
+    to_existing: Vec<String>,
+    to_new: Vec<String>,
+    existing_listening: bool,
+    new_listening: bool,
+}
+
+impl Pairing {
+    fn expired(&self) -> bool {
+        now_millis() > self.expires
+    }
+
+    fn side(&self, auth: u32, server: &Server) -> Option<Side> {
+        if self.claim_secret == Some(auth) {
+            return Some(Side::New);
+        }
+        if server.tokens.lock().get(&auth) == Some(&self.uid) {
+            return Some(Side::Existing);
+        }
+        None
+    }
+
+    fn inbox(&mut self, side: Side) -> &mut Vec<String> {
+        match side {
+            Side::Existing => &mut self.to_existing,
+            Side::New => &mut self.to_new,
+        }
+    }
+}
+
+// a pairing dropped before the new device got its token leaves a session nobody can use, end it
+fn discard(server: &Server, pairing: &Pairing) {
+    if let Some(token) = pairing.new_token {
+        end_session(server, token);
+        info!("linked device never got its token, its session was ended");
+    }
+}
+
+fn prune_expired(server: &Server, pairings: &mut HashMap<u32, Pairing>) {
+    pairings.retain(|_, pairing| {
+        if pairing.expired() {
+            discard(server, pairing);
+        }
+        !pairing.expired()
+    });
+}
+
+// a rename moves approved pairings along so the new device still gets its token, the rest are tied
+// to the old name and dropped, the client can start a new one
+pub fn rename_pairings(pairings: &mut HashMap<u32, Pairing>, old: &UserIdentifier, new: &UserIdentifier) {
+    pairings.retain(|_, pairing| &pairing.uid != old || pairing.approved);
+    for pairing in pairings.values_mut() {
+        if &pairing.uid == old {
+            pairing.uid = new.clone();
+        }
+    }
+}
+
+// cancels the pairing when a side's event stream goes away before the link finished. once it's
+// approved only the new device's stream matters, it's the one still waiting for its token
+struct ListenerGuard {
+    server: Arc<Server>,
+    code: u32,
+    side: Side,
+}
+
+impl Drop for ListenerGuard {
+    fn drop(&mut self) {
+        let mut pairings = self.server.pairings.lock();
+        let approved = match pairings.get(&self.code) {
+            Some(pairing) => pairing.approved,
+            None => return,
+        };
+        if approved && self.side == Side::Existing {
+            return;
+        }
+        if let Some(pairing) = pairings.remove(&self.code) {
+            info!("pairing cancelled, a device disconnected");
+            discard(&self.server, &pairing);
+        }
+    }
+}
+
+#[post("/link/create/<token>")]
+pub fn create_link(token: u32, _limit: UserRateLimit, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let uid = match server.tokens.lock().get(&token) {
+        Some(uid) => uid.clone(),
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    let mut pairings = server.pairings.lock();
+    prune_expired(server, &mut pairings);
+    let mut rng = rand::thread_rng();
+    let mut code = rng.gen_range(0..PAIRING_CODE_LIMIT);
+    while pairings.contains_key(&code) {
+        code = rng.gen_range(0..PAIRING_CODE_LIMIT);
+    }
+    let expires = now_millis() + PAIRING_TIMEOUT_MILLIS;
+    pairings.insert(code, Pairing {
+        uid,
+        expires,
+        claim_secret: None,
+        new_device: None,
+        approved: false,
+        new_token: None,
+        new_client: ClientInfo::default(),
+        to_existing: Vec::new(),
+        to_new: Vec::new(),
+        existing_listening: false,
+        new_listening: false,
+    });
+    (ContentType::JSON, format!("{{\"code\":\"{:08}\", \"expires\":{}}}", code, expires))
+}
+
+// the new device claims the code once, anyone guessing it afterwards is turned away
+#[post("/link/claim/<code>", data = "<new_device>")]
+pub fn claim_link(code: u32, new_device: Json<NewDevice>, client: ClientInfo, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    if new_device.identity_key.as_deref().map(|key| !valid_identity_key(key)).unwrap_or(false) {
+        return (ContentType::JSON, "{\"server\":\"invalid identity key\"}".into());
+    }
+    let mut pairings = server.pairings.lock();
+    prune_expired(server, &mut pairings);
+    let pairing = match pairings.get_mut(&code) {
+        Some(pairing) if pairing.claim_secret.is_none() && !pairing.approved => pairing,
+        _ => return (ContentType::JSON, "{\"server\":\"invalid code\"}".into()),
+    };
+    let secret = rand::random::<u32>();
+    pairing.claim_secret = Some(secret);
+    pairing.new_device = Some(new_device.into_inner());
+    pairing.new_client = client;
+    let claimed = format!(
+        "{{\"claimed\":{{\"name\":{}, \"public_key\":{}}}}}",
+        serde_json::to_string(&pairing.new_device.as_ref().unwrap().name).unwrap(),
+        serde_json::to_string(&pairing.new_device.as_ref().unwrap().public_key).unwrap()
+    );
+    pairing.to_existing.push(claimed);
+    (ContentType::JSON, format!("{{\"secret\":{}, \"username\":{}}}", secret, serde_json::to_string(&pairing.uid.username).unwrap()))
+}
+
+#[post("/link/send/<code>/<auth>", data = "<data>")]
+pub fn send_link_message(code: u32, auth: u32, data: String, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let mut pairings = server.pairings.lock();
+    prune_expired(server, &mut pairings);
+    let pairing = match pairings.get_mut(&code) {
+        Some(pairing) if !pairing.approved => pairing,
+        _ => return (ContentType::JSON, "{\"server\":\"invalid code\"}".into()),
+    };
+    let to = match pairing.side(auth, server) {
+        Some(Side::Existing) if pairing.claim_secret.is_some() => Side::New,
+        Some(Side::New) => Side::Existing,
+        Some(Side::Existing) => return (ContentType::JSON, "{\"server\":\"not claimed yet\"}".into()),
+        None => return (ContentType::JSON, "{\"server\":\"not allowed\"}".into()),
+    };
+    pairing.inbox(to).push(format!("{{\"message\":{}}}", serde_json::to_string(&data).unwrap()));
+    (ContentType::JSON, "{\"server\":\"sent\"}".into())
+}
+
+#[get("/link/receive/<code>/<auth>")]
+pub fn receive_link_messages(code: u32, auth: u32, server_arc: &State<Arc<Server>>) -> TextStream![String + '_] {
+    let side = {
+        let server: &Server = server_arc;
+        let mut pairings = server.pairings.lock();
+        prune_expired(server, &mut pairings);
+        let side = pairings.get(&code).and_then(|pairing| pairing.side(auth, server));
+        if let (Some(side), Some(pairing)) = (side, pairings.get_mut(&code)) {
+            match side {
+                Side::Existing => pairing.existing_listening = true,
+                Side::New => pairing.new_listening = true,
+            }
+        }
+        side
+    };
+    let server_copy = server_arc.inner().clone();
+    TextStream! {
+        let side = match side {
+            Some(side) => side,
+            None => {
+                yield "{\"server\":\"invalid code\"}|endmessage|".to_string();
+                return;
+            }
+        };
+        let _guard = ListenerGuard { server: server_copy, code, side };
+        let mut interval = time::interval(Duration::from_secs(1));
+        loop {
+            let (messages, done) = {
+                let server: &Server = server_arc;
+                let mut pairings = server.pairings.lock();
+                match pairings.get_mut(&code) {
+                    Some(pairing) if !pairing.expired() => (std::mem::take(pairing.inbox(side)), false),
+                    _ => (Vec::new(), true),
+                }
+            };
+            for message in messages {
+                let linked = message.starts_with("{\"linked\"");
+                yield format!("{}|endmessage|", message);
+                if linked {
+                    // the token has been handed over, the code can't be used for anything else
+                    server_arc.pairings.lock().remove(&code);
+                    return;
+                }
+            }
+            if done {
+                yield "{\"server\":\"pairing ended\"}|endmessage|".to_string();
+                return;
+            }
+            interval.tick().await;
+        }
+    }
+}
+
+// the existing device confirms the claimed device, which gets registered and handed its own session
+#[post("/link/approve/<token>/<code>")]
+pub fn approve_link(token: u32, code: u32, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let mut pairings = server.pairings.lock();
+    prune_expired(server, &mut pairings);
+    let pairing = match pairings.get_mut(&code) {
+        Some(pairing) if !pairing.approved => pairing,
+        _ => return (ContentType::JSON, "{\"server\":\"invalid code\"}".into()),
+    };
+    if pairing.side(token, server) != Some(Side::Existing) {
+        return (ContentType::JSON, "{\"server\":\"not allowed\"}".into());
+    }
+    let new_device = match &pairing.new_device {
+        Some(new_device) if pairing.new_listening => new_device.clone(),
+        Some(_) => return (ContentType::JSON, "{\"server\":\"new device not connected\"}".into()),
+        None => return (ContentType::JSON, "{\"server\":\"not claimed yet\"}".into()),
+    };
+    let uid = pairing.uid.clone();
+    let device_id = register_device(
+        &mut server.keys.lock(),
+        &uid,
+        new_device.name,
+        new_device.public_key,
+        new_device.identity_key,
+    );
+    // can't fail, deleting the account removes the pairing first and waits for this lock to do it
+    let new_token = match create_session(server, uid.clone(), Some(device_id), &pairing.new_client) {
+        Some(new_token) => new_token,
+        None => return (ContentType::JSON, "{\"server\":\"invalid code\"}".into()),
+    };
+    pairing.to_new.push(format!("{{\"linked\":{{\"token\":{}, \"device_id\":{}}}}}", new_token, device_id));
+    // single use, the new device's stream picks the token up and removes the pairing. if it never
+    // does, the code is still dead and expires shortly
+    pairing.approved = true;
+    pairing.new_token = Some(new_token);
+    pairing.expires = now_millis() + 30 * 1000;
+    pairing.existing_listening = false;
+    drop(pairings);
+    announce_key_change(&uid, device_id, server);
+    (ContentType::JSON, format!("{{\"device_id\":{}}}", device_id))
+}
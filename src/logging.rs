@@ -0,0 +1,193 @@
+use std::{sync::{Arc, atomic::{AtomicU64, Ordering}}, time::Instant};
+
+use rocket::{Request, Response, Data, fairing::{Fairing, Info, Kind}, http::Header, request::{FromRequest, Outcome}};
+use serde::Deserialize;
+use tracing::{field, Span};
+use tracing_subscriber::EnvFilter;
+
+use crate::{Server, metrics::Metrics, sessions::request_token};
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct LogConfig {
+    // same syntax as RUST_LOG, which overrides it when set. rocket's own request lines carry the
+    // raw path, tokens included, so they stay at warn unless asked for
+    #[serde(default = "default_log_filter")]
+    pub log_filter: String,
+    #[serde(default = "default_log_format")]
+    pub log_format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            log_filter: default_log_filter(),
+            log_format: default_log_format(),
+        }
+    }
+}
+
+fn default_log_filter() -> String {
+    "info,rocket::server=warn,_=warn".to_string()
+}
+
+fn default_log_format() -> LogFormat {
+    LogFormat::Text
+}
+
+// also takes over the `log` crate, so rocket's messages go through the same filter and format
+pub fn init_logging(config: &LogConfig) {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&config.log_filter))
+        .unwrap_or_else(|e| {
+            eprintln!("invalid log_filter {:?}: {e}", config.log_filter);
+            EnvFilter::new(default_log_filter())
+        });
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    let result = match config.log_format {
+        LogFormat::Text => subscriber.try_init(),
+        LogFormat::Json => subscriber.json().with_current_span(true).with_span_list(false).try_init(),
+    };
+    if let Err(e) = result {
+        eprintln!("couldn't set up logging: {e}");
+    }
+}
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+// the span every request runs in, add it as an argument to handlers that log so their events
+// carry the request id. sync handlers can enter it, async ones pass it as the parent
+#[derive(Clone)]
+pub struct RequestSpan {
+    pub id: u64,
+    pub span: Span,
+    started: Instant,
+}
+
+impl RequestSpan {
+    fn none() -> Self {
+        Self { id: 0, span: Span::none(), started: Instant::now() }
+    }
+
+    pub fn enter(&self) -> tracing::span::Entered<'_> {
+        self.span.enter()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestSpan {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let request_span = request.local_cache(RequestSpan::none).clone();
+        identify(request, &request_span.span);
+        Outcome::Success(request_span)
+    }
+}
+
+struct Identified;
+
+// fills in the route and user once the request is routed, local_cache runs this at most once
+fn identify(request: &Request<'_>, span: &Span) {
+    request.local_cache(|| {
+        if let Some(route) = request.route() {
+            span.record("route", field::display(&route.uri));
+        }
+        let user_id = request_token(request)
+            .zip(request.rocket().state::<Arc<Server>>())
+            .and_then(|(token, server_arc)| server_arc.token_user(token).and_then(|uid| server_arc.user_id(&uid)));
+        if let Some(user_id) = user_id {
+            span.record("user_id", user_id);
+        }
+        Identified
+    });
+}
+
+// gives every request an id and a span, and logs one line per request with the route it matched
+// (the template, so no tokens), the user it was for, its status and how long it took
+pub struct RequestTracing;
+
+#[rocket::async_trait]
+impl Fairing for RequestTracing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request tracing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+        let span = tracing::info_span!(
+            "request",
+            request_id = id,
+            method = %request.method(),
+            route = field::Empty,
+            user_id = field::Empty,
+        );
+        request.local_cache(|| RequestSpan { id, span, started: Instant::now() });
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let request_span = request.local_cache(RequestSpan::none);
+        if request_span.id == 0 {
+            return;
+        }
+        let span = &request_span.span;
+        identify(request, span);
+        let status = response.status().code;
+        let elapsed_ms = request_span.started.elapsed().as_millis() as u64;
+        if status >= 500 {
+            tracing::error!(parent: span, status, elapsed_ms, "request failed");
+        } else {
+            tracing::info!(parent: span, status, elapsed_ms, "request finished");
+        }
+        response.set_header(Header::new("X-Request-Id", request_span.id.to_string()));
+    }
+}
+
+// one per event stream connection, logs when it opens and again when it's dropped, however the
+// stream ended. move it into the stream so it lives exactly as long as the connection
+pub struct StreamSpan {
+    pub span: Span,
+    opened: bool,
+    transport: &'static str,
+    metrics: Arc<Metrics>,
+}
+
+impl StreamSpan {
+    pub fn open(server: &Server, token: u32, transport: &'static str) -> Self {
+        let user_id = server.token_user(token).and_then(|uid| server.user_id(&uid));
+        let session = server.sessions.lock().get(&token).map(|session| (session.id, session.device));
+        let span = tracing::info_span!(
+            "event_stream",
+            transport,
+            user_id,
+            session_id = session.map(|(id, _)| id),
+            device = session.and_then(|(_, device)| device),
+        );
+        let opened = user_id.is_some();
+        if opened {
+            server.metrics.stream_opened(transport);
+            tracing::info!(parent: &span, "event stream opened");
+        } else {
+            tracing::debug!(parent: &span, "event stream with an invalid token");
+        }
+        Self { span, opened, transport, metrics: server.metrics.clone() }
+    }
+}
+
+impl Drop for StreamSpan {
+    fn drop(&mut self) {
+        if self.opened {
+            self.metrics.stream_closed(self.transport);
+            tracing::info!(parent: &self.span, "event stream closed");
+        }
+    }
+}
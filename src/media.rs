@@ -0,0 +1,501 @@
+use std::{collections::HashMap, fs, io::{Read, Seek, SeekFrom}, path::{Path, PathBuf}, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+
+use rocket::{Request, Response, State, data::ByteUnit, form::{self, DataField, Form, FromFormField}, http::{ContentType, Header, Status}, request::{FromRequest, Outcome}, response::{self, Responder}, tokio::fs::File};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{Server, message::Message, user::UserIdentifier, user_db::{DBEntryType, UserDB}};
+
+// blobs that no message references yet are kept this long so a client can upload then send
+const GC_GRACE_MILLIS: u128 = 24 * 60 * 60 * 1000;
+
+#[derive(Deserialize, Clone)]
+pub struct MediaConfig {
+    #[serde(default = "default_media_root")]
+    pub media_root: String,
+    #[serde(default = "default_max_attachment_size")]
+    pub max_attachment_size: ByteUnit,
+    #[serde(default = "default_attachment_types")]
+    pub attachment_types: Vec<String>,
+    // base url clients reach this server on, used to build links like pfp urls
+    #[serde(default = "default_public_url")]
+    pub public_url: String,
+    #[serde(default = "default_pfp_root")]
+    pub pfp_root: String,
+    #[serde(default = "default_pfp_sizes")]
+    pub pfp_sizes: Vec<u32>,
+    #[serde(default = "default_pfp_size")]
+    pub default_pfp_size: u32,
+    // Cache-Control max-age for served media, pfp names change on every upload so this can be long
+    #[serde(default = "default_media_max_age")]
+    pub media_max_age: u64,
+}
+
+fn default_media_max_age() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_public_url() -> String {
+    "http://localhost:8000".to_string()
+}
+
+fn default_pfp_root() -> String {
+    "pfps".to_string()
+}
+
+fn default_pfp_sizes() -> Vec<u32> {
+    vec![64, 128, 256, 512]
+}
+
+fn default_pfp_size() -> u32 {
+    256
+}
+
+fn default_media_root() -> String {
+    "media".to_string()
+}
+
+fn default_max_attachment_size() -> ByteUnit {
+    ByteUnit::Mebibyte(25)
+}
+
+fn default_attachment_types() -> Vec<String> {
+    ["image/png", "image/jpeg", "image/gif", "image/webp", "video/mp4", "video/webm", "audio/mpeg", "audio/ogg", "audio/wav", "application/pdf"]
+        .iter()
+        .map(|mime| mime.to_string())
+        .collect()
+}
+
+impl MediaConfig {
+    pub fn blob_dir(&self) -> PathBuf {
+        Path::new(&self.media_root).join("blobs")
+    }
+
+    pub fn blob_path(&self, hash: &str) -> PathBuf {
+        self.blob_dir().join(&hash[..2]).join(hash)
+    }
+}
+
+// what the server knows about a stored blob, keyed by its sha256
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BlobInfo {
+    pub mime: String,
+    pub size: u64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub uploaded: u128,
+    pub uploader: String,
+    // client side encrypted blobs are stored and served as opaque bytes
+    #[serde(default)]
+    pub encrypted: bool,
+    // how many UserDB entries point at this blob, it's only collected once this reaches 0
+    #[serde(default)]
+    pub refs: u64,
+}
+
+// per recipient key material for an encrypted blob, only meaningful to that recipient's client
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyEnvelope {
+    pub key: String,
+    pub nonce: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Attachment {
+    pub hash: String,
+    #[serde(default)]
+    pub mime: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    // reference to the key the client encrypted the blob with, opaque to the server
+    #[serde(default)]
+    pub key_ref: Option<String>,
+    #[serde(default)]
+    pub envelope: Option<KeyEnvelope>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct MediaStore {
+    pub blobs: HashMap<String, BlobInfo>,
+}
+
+impl MediaStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // drops attachments pointing at blobs we don't have and replaces client supplied metadata with ours
+    pub fn verify_attachments(&self, attachments: &Vec<Attachment>) -> Vec<Attachment> {
+        let mut verified = Vec::new();
+        for attachment in attachments {
+            if let Some(info) = self.blobs.get(&attachment.hash) {
+                verified.push(Attachment {
+                    hash: attachment.hash.clone(),
+                    mime: info.mime.clone(),
+                    size: info.size,
+                    width: info.width.or(attachment.width),
+                    height: info.height.or(attachment.height),
+                    key_ref: attachment.key_ref.clone(),
+                    envelope: attachment.envelope.clone(),
+                });
+            }
+        }
+        verified
+    }
+
+    pub fn retain(&mut self, message: &Message) {
+        for attachment in message.all_attachments() {
+            if let Some(info) = self.blobs.get_mut(&attachment.hash) {
+                info.refs += 1;
+            }
+        }
+    }
+
+    pub fn release(&mut self, message: &Message) {
+        for attachment in message.all_attachments() {
+            if let Some(info) = self.blobs.get_mut(&attachment.hash) {
+                info.refs = info.refs.saturating_sub(1);
+            }
+        }
+    }
+
+    // rebuilds every reference count from the UserDBs, used on startup in case the save files drifted
+    pub fn recount(&mut self, user_db: &HashMap<UserIdentifier, UserDB>) {
+        for info in self.blobs.values_mut() {
+            info.refs = 0;
+        }
+        for udb in user_db.values() {
+            for chat_entries in udb.messages.map.values() {
+                for entry in chat_entries.map.values() {
+                    if entry.entry_type == DBEntryType::Message {
+                        self.retain(entry.message.as_ref().unwrap());
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn sniff_mime(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WAVE" {
+        Some("audio/wav")
+    } else if bytes.len() >= 8 && &bytes[4..8] == b"ftyp" {
+        Some("video/mp4")
+    } else if bytes.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        Some("video/webm")
+    } else if bytes.starts_with(b"ID3") || bytes.starts_with(&[0xFF, 0xFB]) {
+        Some("audio/mpeg")
+    } else if bytes.starts_with(b"OggS") {
+        Some("audio/ogg")
+    } else if bytes.starts_with(b"%PDF-") {
+        Some("application/pdf")
+    } else {
+        None
+    }
+}
+
+pub fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
+}
+
+fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+fn read_header(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut header = Vec::new();
+    fs::File::open(path)?.take(32).read_to_end(&mut header)?;
+    Ok(header)
+}
+
+// an attachment streamed straight into the staging dir. reading stops at max_attachment_size, so
+// an oversized upload fails the form with 413 without the rest of it being read. the file is
+// deleted on drop unless store_upload moved it into the store
+pub struct StagedUpload {
+    path: PathBuf,
+}
+
+impl Drop for StagedUpload {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[rocket::async_trait]
+impl<'v> FromFormField<'v> for StagedUpload {
+    async fn from_data(field: DataField<'v, '_>) -> form::Result<'v, Self> {
+        let config = match field.request.rocket().state::<MediaConfig>() {
+            Some(config) => config,
+            None => return Err(form::Error::validation("media isn't configured").into()),
+        };
+        let limit = config.max_attachment_size;
+        let staging_dir = Path::new(&config.media_root).join("staging");
+        fs::create_dir_all(&staging_dir)?;
+        let upload = StagedUpload {
+            path: staging_dir.join(format!("{}", rand::random::<u64>())),
+        };
+        let written = field.data.open(limit).stream_to(File::create(&upload.path).await?).await?;
+        if !written.complete {
+            Err((None, Some(limit.as_u64())))?;
+        }
+        Ok(upload)
+    }
+}
+
+// answers the 413 an oversized upload fails with
+#[catch(413)]
+pub fn payload_too_large() -> (Status, (ContentType, String)) {
+    (Status::PayloadTooLarge, (ContentType::JSON, "{\"server\":\"file too large\"}".to_string()))
+}
+
+// moves an uploaded file into the content addressed store and records it, returning its hash and
+// metadata. identical uploads end up at the same path so they are only stored once, uploading one
+// again restarts its grace period. `allowed_mimes` of None means the upload is an encrypted blob
+// that is stored as opaque bytes without being sniffed
+pub fn store_upload(file: &StagedUpload, config: &MediaConfig, uploader: &UserIdentifier, allowed_mimes: Option<&[String]>, server: &Server) -> Result<(String, BlobInfo), String> {
+    let (hash, info) = inspect_upload(&file.path, uploader, allowed_mimes)?;
+    // collect_garbage deletes under this lock, so it can't remove the blob between it being stored and recorded
+    let mut media = server.media.lock();
+    let blob_path = config.blob_path(&hash);
+    if !blob_path.exists() {
+        fs::create_dir_all(blob_path.parent().unwrap()).map_err(|e| e.to_string())?;
+        fs::rename(&file.path, &blob_path).map_err(|e| e.to_string())?;
+    }
+    media.blobs.entry(hash.clone()).and_modify(|blob| blob.uploaded = info.uploaded).or_insert_with(|| info.clone());
+    Ok((hash, info))
+}
+
+// sniffs and hashes a staged upload
+fn inspect_upload(staging_path: &Path, uploader: &UserIdentifier, allowed_mimes: Option<&[String]>) -> Result<(String, BlobInfo), String> {
+    let mut mime = "application/octet-stream".to_string();
+    let mut width = None;
+    let mut height = None;
+    if let Some(allowed_mimes) = allowed_mimes {
+        let header = read_header(staging_path).map_err(|e| e.to_string())?;
+        mime = sniff_mime(&header).unwrap_or("application/octet-stream").to_string();
+        if !allowed_mimes.contains(&mime) {
+            return Err("file type not allowed".to_string());
+        }
+        if let Ok(reader) = image::io::Reader::open(staging_path).and_then(|reader| reader.with_guessed_format()) {
+            if let Ok((w, h)) = reader.into_dimensions() {
+                width = Some(w);
+                height = Some(h);
+            }
+        }
+    }
+    let size = fs::metadata(staging_path).map_err(|e| e.to_string())?.len();
+    let hash = hash_file(staging_path).map_err(|e| e.to_string())?;
+    Ok((hash, BlobInfo {
+        mime,
+        size,
+        width,
+        height,
+        uploaded: now_millis(),
+        uploader: uploader.username.clone(),
+        encrypted: allowed_mimes.is_none(),
+        refs: 0,
+    }))
+}
+
+// deletes blobs that no UserDB entry references anymore
+pub fn collect_garbage(server: &Server, config: &MediaConfig) -> usize {
+    let mut media = server.media.lock();
+    let now = now_millis();
+    let unreferenced: Vec<String> = media
+        .blobs
+        .iter()
+        .filter(|(_, info)| info.refs == 0 && now.saturating_sub(info.uploaded) > GC_GRACE_MILLIS)
+        .map(|(hash, _)| hash.clone())
+        .collect();
+    for hash in &unreferenced {
+        match fs::remove_file(config.blob_path(hash)) {
+            Ok(()) => {}
+            Err(e) => warn!("couldn't delete blob {hash}: {e}"),
+        }
+        media.blobs.remove(hash);
+    }
+    if !unreferenced.is_empty() {
+        info!(blobs = unreferenced.len(), "garbage collected attachments");
+    }
+    unreferenced.len()
+}
+
+#[derive(FromForm)]
+pub struct AttachmentUpload {
+    file: StagedUpload,
+}
+
+fn upload(token: u32, file: &StagedUpload, allowed_mimes: Option<&[String]>, config: &MediaConfig, server_arc: &Arc<Server>) -> (ContentType, String) {
+    let uid = server_arc.tokens.lock().get(&token).cloned();
+    let uid = match uid {
+        Some(uid) => uid,
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    match store_upload(file, config, &uid, allowed_mimes, server_arc) {
+        Ok((hash, info)) => {
+            let attachment = Attachment {
+                hash: hash.clone(),
+                mime: info.mime.clone(),
+                size: info.size,
+                width: info.width,
+                height: info.height,
+                key_ref: None,
+                envelope: None,
+            };
+            (ContentType::JSON, serde_json::to_string(&attachment).expect("couldn't serialize attachment"))
+        }
+        Err(e) => (ContentType::JSON, format!("{{\"server\":{}}}", serde_json::to_string(&e).unwrap())),
+    }
+}
+
+#[post(
+    "/upload-attachment/<token>",
+    format = "multipart/form-data",
+    data = "<upload_form>"
+)]
+pub fn upload_attachment(token: u32, upload_form: Form<AttachmentUpload>, config: &State<MediaConfig>, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    upload(token, &upload_form.file, Some(&config.attachment_types), config, server_arc)
+}
+
+// the blob is uploaded once and every recipient's SendMessage carries its own KeyEnvelope for it
+#[post(
+    "/upload-encrypted-attachment/<token>",
+    format = "multipart/form-data",
+    data = "<upload_form>"
+)]
+pub fn upload_encrypted_attachment(token: u32, upload_form: Form<AttachmentUpload>, config: &State<MediaConfig>, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    upload(token, &upload_form.file, None, config, server_arc)
+}
+
+pub struct ByteRange(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ByteRange {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ByteRange(request.headers().get_one("Range").map(|range| range.to_string())))
+    }
+}
+
+impl ByteRange {
+    // only single ranges are supported, returns the inclusive start and end
+    fn resolve(&self, size: u64) -> Option<Result<(u64, u64), ()>> {
+        let range = self.0.as_ref()?.trim();
+        let spec = match range.strip_prefix("bytes=") {
+            Some(spec) if !spec.contains(',') => spec,
+            _ => return Some(Err(())),
+        };
+        let (start, end) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return Some(Err(())),
+        };
+        let bounds = match (start.trim(), end.trim()) {
+            ("", suffix) => suffix.parse::<u64>().ok().filter(|suffix| *suffix > 0).map(|suffix| (size.saturating_sub(suffix), size.saturating_sub(1))),
+            (start, "") => start.parse::<u64>().ok().map(|start| (start, size.saturating_sub(1))),
+            (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+                (Ok(start), Ok(end)) if start <= end => Some((start, end.min(size.saturating_sub(1)))),
+                _ => None,
+            },
+        };
+        match bounds {
+            Some((start, end)) if start < size && start <= end => Some(Ok((start, end))),
+            _ => Some(Err(())),
+        }
+    }
+}
+
+pub struct BlobResponse {
+    file: fs::File,
+    content_type: ContentType,
+    size: u64,
+    range: ByteRange,
+}
+
+impl<'r> Responder<'r, 'static> for BlobResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut file = self.file;
+        let mut response = Response::build();
+        response.header(self.content_type).header(Header::new("Accept-Ranges", "bytes"));
+        match self.range.resolve(self.size) {
+            None => {
+                response.sized_body(self.size as usize, rocket::tokio::fs::File::from_std(file));
+            }
+            Some(Ok((start, end))) => {
+                file.seek(SeekFrom::Start(start)).map_err(|_| Status::InternalServerError)?;
+                let mut body = Vec::new();
+                (&mut file).take(end - start + 1).read_to_end(&mut body).map_err(|_| Status::InternalServerError)?;
+                response
+                    .status(Status::PartialContent)
+                    .header(Header::new("Content-Range", format!("bytes {}-{}/{}", start, end, self.size)))
+                    .sized_body(body.len(), std::io::Cursor::new(body));
+            }
+            Some(Err(())) => {
+                response
+                    .status(Status::RangeNotSatisfiable)
+                    .header(Header::new("Content-Range", format!("bytes */{}", self.size)));
+            }
+        }
+        response.ok()
+    }
+}
+
+#[get("/attachment/<token>/<chat>/<hash>")]
+pub fn get_attachment(token: u32, chat: u32, hash: String, range: ByteRange, config: &State<MediaConfig>, server_arc: &State<Arc<Server>>) -> Option<BlobResponse> {
+    let server: &Server = server_arc;
+    let uid = server.tokens.lock().get(&token).cloned()?;
+    if !server.chats.with(&chat, |chat| chat.users.contains(&uid))? {
+        return None;
+    }
+    // being in the chat isn't enough, one of the caller's own messages in it has to reference the blob
+    let referenced = server.user_db.with(&uid, |udb| {
+        udb.messages.get(&chat).map(|entries| {
+            entries
+                .map
+                .values()
+                .any(|entry| entry.message.as_ref().map(|message| message.all_attachments().iter().any(|a| a.hash == hash)).unwrap_or(false))
+        })
+    })??;
+    if !referenced {
+        return None;
+    }
+    let info = server.media.lock().blobs.get(&hash)?.clone();
+    let content_type = ContentType::parse_flexible(&info.mime).unwrap_or(ContentType::Binary);
+    let file = fs::File::open(config.blob_path(&hash)).ok()?;
+    Some(BlobResponse {
+        file,
+        content_type,
+        size: info.size,
+        range,
+    })
+}
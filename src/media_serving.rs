@@ -0,0 +1,138 @@
+use std::{fs, io::Read, path::{Component, Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use rocket::{Request, Response, State, http::{ContentType, Header, Status}, request::{FromRequest, Outcome}, response::{self, Responder}};
+use tracing::{debug, warn};
+
+use crate::{media::{sniff_mime, MediaConfig}, pfp::{pfp_variant_name, pick_pfp_size}};
+
+// resolves `name` to a file directly inside `root`. anything that could walk out of the root
+// (separators, `..`, absolute paths, symlinks pointing elsewhere) resolves to None
+pub fn resolve(root: &str, name: &str) -> Option<PathBuf> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => {}
+        _ => return None,
+    }
+    if name.contains('/') || name.contains('\\') || name.contains(':') || name.starts_with('.') {
+        return None;
+    }
+    let root = fs::canonicalize(root).ok()?;
+    let path = root.join(name);
+    match fs::canonicalize(&path) {
+        Ok(resolved) if resolved.starts_with(&root) => Some(resolved),
+        Ok(_) => None,
+        // the file doesn't exist yet, it's safe to create since `name` is a single plain component
+        Err(_) => Some(path),
+    }
+}
+
+pub struct Conditional {
+    if_none_match: Option<String>,
+    if_modified_since: Option<SystemTime>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Conditional {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Conditional {
+            if_none_match: request.headers().get_one("If-None-Match").map(|etag| etag.to_string()),
+            if_modified_since: request
+                .headers()
+                .get_one("If-Modified-Since")
+                .and_then(|date| httpdate::parse_http_date(date).ok()),
+        })
+    }
+}
+
+// a file served with validators so browsers only redownload it when it actually changed
+pub struct CachedFile {
+    file: fs::File,
+    content_type: ContentType,
+    etag: String,
+    modified: SystemTime,
+    max_age: u64,
+    not_modified: bool,
+}
+
+impl CachedFile {
+    pub fn open(path: &Path, conditional: &Conditional, max_age: u64) -> Option<Self> {
+        let mut file = fs::File::open(path).ok()?;
+        let metadata = file.metadata().ok()?;
+        if !metadata.is_file() {
+            return None;
+        }
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let modified_secs = modified.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+        let etag = format!("\"{:x}-{:x}\"", modified_secs, metadata.len());
+        let mut header = Vec::new();
+        (&mut file).take(32).read_to_end(&mut header).ok()?;
+        let content_type = sniff_mime(&header)
+            .and_then(ContentType::parse_flexible)
+            .unwrap_or(ContentType::Binary);
+        let not_modified = match (&conditional.if_none_match, conditional.if_modified_since) {
+            (Some(if_none_match), _) => if_none_match.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"),
+            (None, Some(since)) => since.duration_since(UNIX_EPOCH).map(|since| since.as_secs() >= modified_secs).unwrap_or(false),
+            (None, None) => false,
+        };
+        Some(Self {
+            file: fs::File::open(path).ok()?,
+            content_type,
+            etag,
+            modified,
+            max_age,
+            not_modified,
+        })
+    }
+}
+
+impl<'r> Responder<'r, 'static> for CachedFile {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response
+            .header(Header::new("ETag", self.etag))
+            .header(Header::new("Last-Modified", httpdate::fmt_http_date(self.modified)))
+            .header(Header::new("Cache-Control", format!("public, max-age={}", self.max_age)));
+        if self.not_modified {
+            response.status(Status::NotModified);
+        } else {
+            let size = self.file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+            response
+                .header(self.content_type)
+                .sized_body(size as usize, rocket::tokio::fs::File::from_std(self.file));
+        }
+        response.ok()
+    }
+}
+
+#[get("/pfps/<pfp>?<size>")]
+pub fn get_pfp(pfp: String, size: Option<u32>, conditional: Conditional, config: &State<MediaConfig>) -> Option<CachedFile> {
+    let size = pick_pfp_size(config, size)?;
+    let path = match resolve(&config.pfp_root, &pfp_variant_name(&pfp, size)).filter(|path| path.is_file()) {
+        Some(path) => path,
+        // pfps uploaded before resizing existed are stored as-is under their full name
+        None => resolve(&config.pfp_root, &pfp)?,
+    };
+    CachedFile::open(&path, &conditional, config.media_max_age)
+}
+
+// deletes every file belonging to a pfp url we handed out, called when it gets replaced or deleted
+pub fn remove_pfp_files(config: &MediaConfig, pfp_url: &str) {
+    let name = match pfp_url.rsplit_once("/pfps/") {
+        Some((_, name)) => name,
+        None => return,
+    };
+    let mut names = vec![name.to_string()];
+    for size in &config.pfp_sizes {
+        names.push(pfp_variant_name(name, *size));
+    }
+    for name in names {
+        if let Some(path) = resolve(&config.pfp_root, &name).filter(|path| path.is_file()) {
+            match fs::remove_file(&path) {
+                Ok(()) => debug!("removed old pfp {}", path.display()),
+                Err(e) => warn!("couldn't remove old pfp {}: {e}", path.display()),
+            }
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use crate::user::UserIdentifier;
+use crate::{media::Attachment, user::UserIdentifier};
 use serde::Serialize;
 use rocket::serde::Deserialize;
 
@@ -9,61 +9,139 @@ pub struct Message {
     pub id: u32,
     pub text: String,
     pub from_user: UserIdentifier,
+    // the sender's immutable user id, from_user follows renames but this is what stays stable
+    #[serde(default)]
+    pub from_id: u32,
     pub chat: u32,
     pub timestamp: u128,
     pub read: String,
     pub reactions: HashMap<String, String>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    // ciphertext for each of the recipient's registered devices, keyed by device id
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub device_copies: HashMap<u32, DeviceCopy>,
+    // when the server deletes it, from the chat's expiry at the time it was sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u128>,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct DeviceCopy {
+    pub text: String,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+}
+
+impl Message {
+    // the message as `device` should see it: its own copy if it has one and none of the other devices' copies
+    pub fn for_device(&self, device: Option<u32>) -> Message {
+        let mut message = self.clone();
+        message.device_copies = HashMap::new();
+        if let Some(copy) = device.and_then(|device| self.device_copies.get(&device)) {
+            message.text = copy.text.clone();
+            message.attachments = copy.attachments.clone();
+        }
+        message
+    }
+
+    pub fn all_attachments(&self) -> Vec<&Attachment> {
+        let mut attachments: Vec<&Attachment> = self.attachments.iter().collect();
+        for copy in self.device_copies.values() {
+            attachments.extend(copy.attachments.iter());
+        }
+        attachments
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct SendMessage {
     pub text: String,
     pub from_user: u32,
     pub chat: u32,
     pub timestamp: u128,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct EncryptedMessages {
+    // keyed by username, for recipients whose devices all share the key on their profile
+    #[serde(default)]
     pub encrypted_messages: HashMap<String, SendMessage>,
+    // keyed by username then device id, for recipients with their own per device keys
+    #[serde(default)]
+    pub device_messages: HashMap<String, HashMap<u32, SendMessage>>,
+}
+
+impl EncryptedMessages {
+    pub fn recipients(&self) -> Vec<String> {
+        let mut recipients: Vec<String> = self.encrypted_messages.keys().cloned().collect();
+        for username in self.device_messages.keys() {
+            if !recipients.contains(username) {
+                recipients.push(username.clone());
+            }
+        }
+        recipients
+    }
 }
 
 impl SendMessage {
-    pub fn to_message(&self, id: u32, from_user: UserIdentifier ) -> Message {
+    pub fn to_message(&self, id: u32, from_user: UserIdentifier, from_id: u32) -> Message {
         Message {
             id,
             text: self.text.clone(),
             from_user,
+            from_id,
             chat: self.chat,
             timestamp: self.timestamp,
             read: "Sent".into(),
             reactions: HashMap::new(),
+            attachments: self.attachments.clone(),
+            device_copies: HashMap::new(),
+            expires_at: None,
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Chat {
     pub users: Vec<UserIdentifier>,
     pub name: String,
     pub id: u32,
     pub admin: UserIdentifier,
+    // chats that don't use end to end encryption can opt in to server side full text search
+    #[serde(default)]
+    pub searchable: bool,
+    // seconds messages sent from now on are kept for, None keeps them forever
+    #[serde(default)]
+    pub expire_after: Option<u64>,
 }
 
 #[derive(Deserialize)]
 pub struct CreateChat {
     pub users: Vec<UserIdentifier>,
     pub name: String,
-    pub admin: UserIdentifier,
+    #[serde(default)]
+    pub searchable: bool,
+    #[serde(default)]
+    pub expire_after: Option<u64>,
 }
 
 impl CreateChat {
-    pub fn to_chat(&self, id: u32) -> Chat {
+    // the creator is the admin and always a member
+    pub fn to_chat(&self, id: u32, admin: UserIdentifier) -> Chat {
+        let mut users = self.users.clone();
+        if !users.contains(&admin) {
+            users.push(admin.clone());
+        }
         Chat {
-            users: self.users.clone(),
+            users,
             name: self.name.clone(),
             id,
-            admin: self.admin.clone(),
+            admin,
+            searchable: self.searchable,
+            expire_after: self.expire_after,
         }
     }
 }
@@ -73,4 +151,9 @@ pub struct ChatEdit {
     pub added_users: Vec<UserIdentifier>,
     pub new_name: String,
     pub new_admin: UserIdentifier,
+    #[serde(default)]
+    pub searchable: Option<bool>,
+    // seconds, 0 turns expiry off
+    #[serde(default)]
+    pub expire_after: Option<u64>,
 }
\ No newline at end of file
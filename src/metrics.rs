@@ -0,0 +1,306 @@
+use std::{collections::BTreeMap, fmt::Write, sync::{Arc, atomic::{AtomicI64, AtomicU64, Ordering}}, time::{Duration, Instant}};
+
+use rocket::{Data, Request, Response, State, fairing::{Fairing, Info, Kind}, http::{ContentType, Status}, request::{FromRequest, Outcome}};
+use parking_lot::Mutex;
+use serde::Deserialize;
+
+use crate::Server;
+
+const DURATION_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const FANOUT_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0];
+
+#[derive(Deserialize, Clone, Default)]
+pub struct MetricsConfig {
+    // /metrics answers 404 unless this is on
+    #[serde(default)]
+    pub metrics_enabled: bool,
+    // when set, scrapes need `Authorization: Bearer <metrics_token>`
+    #[serde(default)]
+    pub metrics_token: Option<String>,
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    // one per bound, not cumulative, the +Inf bucket is `count`
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self { bounds, buckets: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{{labels}{separator}le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels}{separator}le=\"+Inf\"}} {}", self.count);
+        let braces = if labels.is_empty() { String::new() } else { format!("{{{labels}}}") };
+        let _ = writeln!(out, "{name}_sum{braces} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{braces} {}", self.count);
+    }
+}
+
+// everything is either atomic or behind its own short lived lock, so recording never waits on the
+// server's stores and the Server lock order doesn't apply here
+pub struct Metrics {
+    // (method, route, status)
+    http_requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    http_durations: Mutex<BTreeMap<String, Histogram>>,
+    sse_streams: AtomicI64,
+    websocket_streams: AtomicI64,
+    events_sent: AtomicU64,
+    sends_dropped: AtomicU64,
+    fanout_streams: Mutex<Histogram>,
+    fanout_durations: Mutex<Histogram>,
+    persist_durations: Mutex<Histogram>,
+    persist_failures: AtomicU64,
+    persist_bytes: AtomicU64,
+    // entries removed by retention, by which limit removed them
+    retention_pruned: Mutex<BTreeMap<&'static str, u64>>,
+    retention_durations: Mutex<Histogram>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            http_requests: Mutex::new(BTreeMap::new()),
+            http_durations: Mutex::new(BTreeMap::new()),
+            sse_streams: AtomicI64::new(0),
+            websocket_streams: AtomicI64::new(0),
+            events_sent: AtomicU64::new(0),
+            sends_dropped: AtomicU64::new(0),
+            fanout_streams: Mutex::new(Histogram::new(FANOUT_BUCKETS)),
+            fanout_durations: Mutex::new(Histogram::new(DURATION_BUCKETS)),
+            persist_durations: Mutex::new(Histogram::new(DURATION_BUCKETS)),
+            persist_failures: AtomicU64::new(0),
+            persist_bytes: AtomicU64::new(0),
+            retention_pruned: Mutex::new(BTreeMap::new()),
+            retention_durations: Mutex::new(Histogram::new(DURATION_BUCKETS)),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        *self.http_requests.lock().entry((method.to_string(), route.to_string(), status)).or_insert(0) += 1;
+        self.http_durations
+            .lock()
+            .entry(route.to_string())
+            .or_insert_with(|| Histogram::new(DURATION_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    fn streams(&self, transport: &str) -> &AtomicI64 {
+        match transport {
+            "websocket" => &self.websocket_streams,
+            _ => &self.sse_streams,
+        }
+    }
+
+    pub fn stream_opened(&self, transport: &str) {
+        self.streams(transport).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stream_closed(&self, transport: &str) {
+        self.streams(transport).fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn open_streams(&self, transport: &str) -> i64 {
+        self.streams(transport).load(Ordering::Relaxed)
+    }
+
+    // one call per recipient of a send_sendable or send_message
+    pub fn record_sends(&self, delivered: u64, dropped: u64) {
+        self.events_sent.fetch_add(delivered, Ordering::Relaxed);
+        self.sends_dropped.fetch_add(dropped, Ordering::Relaxed);
+    }
+
+    // one call per send_message, with the streams that recipient's copy reached
+    pub fn record_fanout(&self, streams: u64, elapsed: Duration) {
+        self.fanout_streams.lock().observe(streams as f64);
+        self.fanout_durations.lock().observe(elapsed.as_secs_f64());
+    }
+
+    // bytes is None when the save failed
+    pub fn record_persist(&self, elapsed: Duration, bytes: Option<u64>) {
+        self.persist_durations.lock().observe(elapsed.as_secs_f64());
+        match bytes {
+            Some(bytes) => self.persist_bytes.store(bytes, Ordering::Relaxed),
+            None => {
+                self.persist_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // one call per compaction, with how many entries each limit removed
+    pub fn record_retention(&self, pruned: &BTreeMap<&'static str, u64>, elapsed: Duration) {
+        self.retention_durations.lock().observe(elapsed.as_secs_f64());
+        let mut totals = self.retention_pruned.lock();
+        for (reason, count) in pruned {
+            *totals.entry(reason).or_insert(0) += count;
+        }
+    }
+
+    // prometheus text format, the store sizes are read from the server at scrape time
+    pub fn render(&self, server: &Server) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP messenger_http_requests_total HTTP requests by route and status.\n");
+        out.push_str("# TYPE messenger_http_requests_total counter\n");
+        for ((method, route, status), count) in self.http_requests.lock().iter() {
+            let _ = writeln!(out, "messenger_http_requests_total{{method=\"{method}\",route=\"{}\",status=\"{status}\"}} {count}", escape(route));
+        }
+        out.push_str("# HELP messenger_http_request_duration_seconds Time from receiving a request to responding.\n");
+        out.push_str("# TYPE messenger_http_request_duration_seconds histogram\n");
+        for (route, histogram) in self.http_durations.lock().iter() {
+            histogram.render(&mut out, "messenger_http_request_duration_seconds", &format!("route=\"{}\"", escape(route)));
+        }
+
+        out.push_str("# HELP messenger_event_streams Connected event stream subscribers.\n");
+        out.push_str("# TYPE messenger_event_streams gauge\n");
+        let _ = writeln!(out, "messenger_event_streams{{transport=\"sse\"}} {}", self.sse_streams.load(Ordering::Relaxed));
+        let _ = writeln!(out, "messenger_event_streams{{transport=\"websocket\"}} {}", self.websocket_streams.load(Ordering::Relaxed));
+        out.push_str("# HELP messenger_events_sent_total Events handed to a connected stream.\n");
+        out.push_str("# TYPE messenger_events_sent_total counter\n");
+        let _ = writeln!(out, "messenger_events_sent_total {}", self.events_sent.load(Ordering::Relaxed));
+        out.push_str("# HELP messenger_sends_dropped_total Sends to a stream that had already gone away.\n");
+        out.push_str("# TYPE messenger_sends_dropped_total counter\n");
+        let _ = writeln!(out, "messenger_sends_dropped_total {}", self.sends_dropped.load(Ordering::Relaxed));
+        out.push_str("# HELP messenger_message_fanout_streams Streams one recipient's copy of a message reached.\n");
+        out.push_str("# TYPE messenger_message_fanout_streams histogram\n");
+        self.fanout_streams.lock().render(&mut out, "messenger_message_fanout_streams", "");
+        out.push_str("# HELP messenger_message_fanout_duration_seconds Time to deliver, store and index a message for one recipient.\n");
+        out.push_str("# TYPE messenger_message_fanout_duration_seconds histogram\n");
+        self.fanout_durations.lock().render(&mut out, "messenger_message_fanout_duration_seconds", "");
+
+        out.push_str("# HELP messenger_persist_duration_seconds Time taken to write the save files.\n");
+        out.push_str("# TYPE messenger_persist_duration_seconds histogram\n");
+        self.persist_durations.lock().render(&mut out, "messenger_persist_duration_seconds", "");
+        out.push_str("# HELP messenger_persist_failures_total Saves that failed.\n");
+        out.push_str("# TYPE messenger_persist_failures_total counter\n");
+        let _ = writeln!(out, "messenger_persist_failures_total {}", self.persist_failures.load(Ordering::Relaxed));
+        out.push_str("# HELP messenger_persist_bytes Size of the save files after the last successful save.\n");
+        out.push_str("# TYPE messenger_persist_bytes gauge\n");
+        let _ = writeln!(out, "messenger_persist_bytes {}", self.persist_bytes.load(Ordering::Relaxed));
+
+        out.push_str("# HELP messenger_retention_pruned_total History entries removed by retention, by the limit that removed them.\n");
+        out.push_str("# TYPE messenger_retention_pruned_total counter\n");
+        for (reason, count) in self.retention_pruned.lock().iter() {
+            let _ = writeln!(out, "messenger_retention_pruned_total{{reason=\"{reason}\"}} {count}");
+        }
+        out.push_str("# HELP messenger_retention_duration_seconds Time taken by one retention compaction.\n");
+        out.push_str("# TYPE messenger_retention_duration_seconds histogram\n");
+        self.retention_durations.lock().render(&mut out, "messenger_retention_duration_seconds", "");
+
+        let sessions = server.sessions.lock().len();
+        let users = server.users.lock().len();
+        let chats = server.chats.len();
+        out.push_str("# HELP messenger_sessions Active login sessions.\n");
+        out.push_str("# TYPE messenger_sessions gauge\n");
+        let _ = writeln!(out, "messenger_sessions {sessions}");
+        out.push_str("# HELP messenger_users Registered users.\n");
+        out.push_str("# TYPE messenger_users gauge\n");
+        let _ = writeln!(out, "messenger_users {users}");
+        out.push_str("# HELP messenger_chats Chats.\n");
+        out.push_str("# TYPE messenger_chats gauge\n");
+        let _ = writeln!(out, "messenger_chats {chats}");
+        out
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+struct RequestStarted(Instant);
+
+// counts every request under the route it matched, the route template keeps the label set small
+pub struct HttpMetrics;
+
+#[rocket::async_trait]
+impl Fairing for HttpMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "HTTP metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStarted(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let server_arc = match request.rocket().state::<Arc<Server>>() {
+            Some(server_arc) => server_arc,
+            None => return,
+        };
+        let route = request.route().map(|route| route.uri.to_string()).unwrap_or_else(|| "unmatched".to_string());
+        let started = request.local_cache(|| RequestStarted(Instant::now()));
+        server_arc.metrics.record_request(request.method().as_str(), &route, response.status().code, started.0.elapsed());
+    }
+}
+
+// lets a scrape through when metrics are on and, if a token is configured, it's presented
+pub struct MetricsAuth;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsAuth {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let config = match request.rocket().state::<MetricsConfig>() {
+            Some(config) if config.metrics_enabled => config,
+            _ => return Outcome::Failure((Status::NotFound, ())),
+        };
+        let expected = match &config.metrics_token {
+            Some(token) => token,
+            None => return Outcome::Success(MetricsAuth),
+        };
+        let presented = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+        match presented {
+            Some(presented) if constant_time_eq(presented.as_bytes(), expected.as_bytes()) => Outcome::Success(MetricsAuth),
+            _ => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[get("/metrics")]
+pub fn get_metrics(_auth: MetricsAuth, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    (ContentType::new("text", "plain").with_params(("version", "0.0.4")), server.metrics.render(server))
+}
+
+// how big a save is, summed over the files in the save directory
+pub fn save_size(dir: &str) -> u64 {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
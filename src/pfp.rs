@@ -0,0 +1,62 @@
+use std::{fs, io::Cursor, path::{Path, PathBuf}};
+
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage};
+
+use crate::media::{sniff_mime, MediaConfig};
+
+const PFP_JPEG_QUALITY: u8 = 85;
+const PFP_MIMES: [&str; 4] = ["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+// decodes an uploaded pfp, crops it to a centered square and writes one jpeg per configured size.
+// re-encoding from decoded pixels is what strips EXIF and anything else riding along in the original.
+// returns the name the pfp is served under, sizes are picked with `?size=`. it's built from the
+// immutable user id, nothing the user typed ends up in a path
+pub fn process_pfp(upload: &Path, user_id: u32, config: &MediaConfig) -> Result<String, String> {
+    let bytes = fs::read(upload).map_err(|e| e.to_string())?;
+    let mime = sniff_mime(&bytes);
+    if !mime.map(|mime| PFP_MIMES.contains(&mime)).unwrap_or(false) {
+        return Err("not an image".to_string());
+    }
+    let image = image::load_from_memory(&bytes).map_err(|e| e.to_string())?;
+    let side = image.width().min(image.height());
+    if side == 0 {
+        return Err("empty image".to_string());
+    }
+    let square = image.crop_imm((image.width() - side) / 2, (image.height() - side) / 2, side, side);
+    let name = format!("{:08x}-{:08x}", user_id, rand::random::<u32>());
+    fs::create_dir_all(&config.pfp_root).map_err(|e| e.to_string())?;
+    for size in &config.pfp_sizes {
+        let resized = square.resize_exact(*size, *size, FilterType::Lanczos3);
+        let encoded = encode_jpeg(&resized)?;
+        fs::write(pfp_variant_path(config, &name, *size), encoded).map_err(|e| e.to_string())?;
+    }
+    Ok(name)
+}
+
+fn encode_jpeg(image: &DynamicImage) -> Result<Vec<u8>, String> {
+    let mut encoded = Cursor::new(Vec::new());
+    JpegEncoder::new_with_quality(&mut encoded, PFP_JPEG_QUALITY)
+        .encode_image(&DynamicImage::ImageRgb8(image.to_rgb8()))
+        .map_err(|e| e.to_string())?;
+    Ok(encoded.into_inner())
+}
+
+pub fn pfp_variant_name(name: &str, size: u32) -> String {
+    format!("{}-{}.jpg", name, size)
+}
+
+pub fn pfp_variant_path(config: &MediaConfig, name: &str, size: u32) -> PathBuf {
+    Path::new(&config.pfp_root).join(pfp_variant_name(name, size))
+}
+
+// smallest configured size that is at least as big as requested, falling back to the largest
+pub fn pick_pfp_size(config: &MediaConfig, requested: Option<u32>) -> Option<u32> {
+    let requested = requested.unwrap_or(config.default_pfp_size);
+    let mut sizes = config.pfp_sizes.clone();
+    sizes.sort_unstable();
+    sizes.iter().find(|size| **size >= requested).or(sizes.last()).copied()
+}
+
+pub fn pfp_url(config: &MediaConfig, name: &str) -> String {
+    format!("{}/pfps/{}", config.public_url.trim_end_matches('/'), name)
+}
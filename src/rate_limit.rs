@@ -0,0 +1,476 @@
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use rocket::{Data, Request, Response, fairing::{Fairing, Info, Kind}, http::{ContentType, Header, Method, Status, uri::Origin}, request::{FromRequest, Outcome}, response::{self, Responder}};
+use parking_lot::Mutex;
+use serde::Deserialize;
+
+use crate::{Server, clock::Clock, sessions::request_token};
+
+// buckets that have refilled completely carry no state, they get dropped once there are this many
+const PRUNE_THRESHOLD: usize = 10_000;
+
+// `capacity` requests at once, refilled evenly over `per_seconds`
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct LimitRule {
+    pub capacity: u32,
+    pub per_seconds: u64,
+}
+
+impl LimitRule {
+    fn millis_per_token(&self) -> f64 {
+        (self.per_seconds as f64 * 1000.0) / self.capacity.max(1) as f64
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RateLimitConfig {
+    #[serde(default = "default_per_ip")]
+    pub rate_limit_per_ip: LimitRule,
+    #[serde(default = "default_per_user")]
+    pub rate_limit_per_user: LimitRule,
+    // keyed by the first path segment, e.g. "login" or "post-message", counted per ip
+    #[serde(default = "default_routes")]
+    pub rate_limit_routes: HashMap<String, LimitRule>,
+    // failed logins for a username from one ip before lockouts start
+    #[serde(default = "default_login_free_attempts")]
+    pub login_free_attempts: u32,
+    // failed logins for a username from every ip together before the account itself is locked, so
+    // spreading guesses over many ips doesn't get around the lockout
+    #[serde(default = "default_login_account_free_attempts")]
+    pub login_account_free_attempts: u32,
+    // first lockout, doubled on every further failure
+    #[serde(default = "default_login_lockout_seconds")]
+    pub login_lockout_seconds: u64,
+    #[serde(default = "default_login_lockout_max_seconds")]
+    pub login_lockout_max_seconds: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            rate_limit_per_ip: default_per_ip(),
+            rate_limit_per_user: default_per_user(),
+            rate_limit_routes: default_routes(),
+            login_free_attempts: default_login_free_attempts(),
+            login_account_free_attempts: default_login_account_free_attempts(),
+            login_lockout_seconds: default_login_lockout_seconds(),
+            login_lockout_max_seconds: default_login_lockout_max_seconds(),
+        }
+    }
+}
+
+fn default_per_ip() -> LimitRule {
+    LimitRule { capacity: 300, per_seconds: 60 }
+}
+
+fn default_per_user() -> LimitRule {
+    LimitRule { capacity: 120, per_seconds: 60 }
+}
+
+fn default_routes() -> HashMap<String, LimitRule> {
+    let mut routes = HashMap::new();
+    routes.insert("login".to_string(), LimitRule { capacity: 10, per_seconds: 60 });
+    routes.insert("create-account".to_string(), LimitRule { capacity: 3, per_seconds: 3600 });
+    routes.insert("create-chat".to_string(), LimitRule { capacity: 20, per_seconds: 3600 });
+    routes.insert("create-chat-link".to_string(), LimitRule { capacity: 20, per_seconds: 3600 });
+    routes.insert("post-message".to_string(), LimitRule { capacity: 60, per_seconds: 60 });
+    routes.insert("link".to_string(), LimitRule { capacity: 20, per_seconds: 60 });
+    routes.insert("events-ws".to_string(), LimitRule { capacity: 10, per_seconds: 60 });
+    routes
+}
+
+fn default_login_free_attempts() -> u32 {
+    3
+}
+
+fn default_login_account_free_attempts() -> u32 {
+    10
+}
+
+fn default_login_lockout_seconds() -> u64 {
+    2
+}
+
+fn default_login_lockout_max_seconds() -> u64 {
+    15 * 60
+}
+
+#[derive(Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: u128,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, rule: &LimitRule, now: u128) {
+        let elapsed = now.saturating_sub(self.updated) as f64;
+        self.tokens = (self.tokens + elapsed / rule.millis_per_token()).min(rule.capacity as f64);
+        self.updated = now;
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+enum BucketKey {
+    Ip(IpAddr),
+    User(String),
+    Route(String, IpAddr),
+}
+
+#[derive(Default)]
+struct LoginFailures {
+    count: u32,
+    locked_until: u128,
+}
+
+impl LoginFailures {
+    // every failure past the free ones doubles the lockout, up to the configured maximum
+    fn record(&mut self, free_attempts: u32, config: &RateLimitConfig, now: u128) {
+        self.count += 1;
+        if self.count > free_attempts {
+            let doublings = (self.count - free_attempts - 1).min(32);
+            let lockout = config.login_lockout_seconds.saturating_mul(1u64 << doublings).min(config.login_lockout_max_seconds);
+            self.locked_until = now + lockout as u128 * 1000;
+        }
+    }
+
+    fn wait(&self, now: u128) -> u128 {
+        self.locked_until.saturating_sub(now)
+    }
+}
+
+pub struct RateLimiter {
+    pub config: RateLimitConfig,
+    clock: Arc<dyn Clock>,
+    buckets: Mutex<HashMap<BucketKey, TokenBucket>>,
+    login_failures: Mutex<HashMap<(String, Option<IpAddr>), LoginFailures>>,
+    // by lowercased username, across every ip
+    account_failures: Mutex<HashMap<String, LoginFailures>>,
+}
+
+// seconds until the request would be allowed
+pub type RetryAfter = u64;
+
+fn millis_to_retry_after(millis: f64) -> RetryAfter {
+    ((millis / 1000.0).ceil() as u64).max(1)
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            config,
+            clock,
+            buckets: Mutex::new(HashMap::new()),
+            login_failures: Mutex::new(HashMap::new()),
+            account_failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // takes one token from every bucket or none of them, so a rejected request doesn't count
+    fn take(&self, checks: &[(BucketKey, LimitRule)]) -> Result<(), RetryAfter> {
+        let now = self.clock.now_millis();
+        let mut buckets = self.buckets.lock();
+        if buckets.len() > PRUNE_THRESHOLD {
+            let rules: HashMap<&BucketKey, &LimitRule> = checks.iter().map(|(key, rule)| (key, rule)).collect();
+            let longest = self.longest_refill_millis();
+            buckets.retain(|key, bucket| rules.contains_key(key) || now.saturating_sub(bucket.updated) < longest);
+        }
+        let mut wait: f64 = 0.0;
+        for (key, rule) in checks {
+            let bucket = buckets.entry(key.clone()).or_insert(TokenBucket { tokens: rule.capacity as f64, updated: now });
+            bucket.refill(rule, now);
+            if bucket.tokens < 1.0 {
+                wait = wait.max((1.0 - bucket.tokens) * rule.millis_per_token());
+            }
+        }
+        if wait > 0.0 {
+            return Err(millis_to_retry_after(wait));
+        }
+        for (key, _) in checks {
+            buckets.get_mut(key).unwrap().tokens -= 1.0;
+        }
+        Ok(())
+    }
+
+    fn longest_refill_millis(&self) -> u128 {
+        let mut rules = vec![self.config.rate_limit_per_ip, self.config.rate_limit_per_user];
+        rules.extend(self.config.rate_limit_routes.values().copied());
+        rules.iter().map(|rule| rule.per_seconds as u128 * 1000).max().unwrap_or(0)
+    }
+
+    // per ip, plus the route's own limit for that ip if it has one
+    pub fn check_request(&self, ip: Option<IpAddr>, route: &str) -> Result<(), RetryAfter> {
+        let ip = match ip {
+            Some(ip) => ip,
+            None => return Ok(()),
+        };
+        let mut checks = vec![(BucketKey::Ip(ip), self.config.rate_limit_per_ip)];
+        if let Some(rule) = self.config.rate_limit_routes.get(route) {
+            checks.push((BucketKey::Route(route.to_string(), ip), *rule));
+        }
+        self.take(&checks)
+    }
+
+    pub fn check_user(&self, username: &str) -> Result<(), RetryAfter> {
+        self.take(&[(BucketKey::User(username.to_string()), self.config.rate_limit_per_user)])
+    }
+
+    // locked if either this ip or the account as a whole is, for whichever lasts longer
+    pub fn login_locked(&self, username: &str, ip: Option<IpAddr>) -> Result<(), RetryAfter> {
+        let now = self.clock.now_millis();
+        let username = username.to_lowercase();
+        let from_ip = self.login_failures.lock().get(&(username.clone(), ip)).map(|failures| failures.wait(now)).unwrap_or(0);
+        let account = self.account_failures.lock().get(&username).map(|failures| failures.wait(now)).unwrap_or(0);
+        match from_ip.max(account) {
+            0 => Ok(()),
+            wait => Err(millis_to_retry_after(wait as f64)),
+        }
+    }
+
+    pub fn login_failed(&self, username: &str, ip: Option<IpAddr>) {
+        let now = self.clock.now_millis();
+        let username = username.to_lowercase();
+        self.login_failures.lock().entry((username.clone(), ip)).or_default().record(self.config.login_free_attempts, &self.config, now);
+        self.account_failures.lock().entry(username).or_default().record(self.config.login_account_free_attempts, &self.config, now);
+    }
+
+    pub fn login_succeeded(&self, username: &str, ip: Option<IpAddr>) {
+        let username = username.to_lowercase();
+        self.login_failures.lock().remove(&(username.clone(), ip));
+        self.account_failures.lock().remove(&username);
+    }
+}
+
+pub fn route_name(path: &str) -> &str {
+    path.trim_start_matches('/').split('/').next().unwrap_or("")
+}
+
+// 429 with the number of seconds to wait, as a header and in the body
+pub struct TooManyRequests(pub RetryAfter);
+
+impl<'r> Responder<'r, 'static> for TooManyRequests {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let body = format!("{{\"server\":\"rate limited\", \"retry_after\":{}}}", self.0);
+        Response::build_from((ContentType::JSON, body).respond_to(request)?)
+            .status(Status::TooManyRequests)
+            .header(Header::new("Retry-After", self.0.to_string()))
+            .ok()
+    }
+}
+
+// set by the fairing or the guard when a request was turned away
+struct Limited(Option<RetryAfter>);
+
+// applies the ip and route limits to every request. fairings can't answer a request themselves,
+// so a limited one is rerouted to /rate-limited which fails with 429 and the catcher fills in Retry-After
+pub struct RateLimitFairing(pub Arc<RateLimiter>);
+
+#[rocket::async_trait]
+impl Fairing for RateLimitFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limiting",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        if request.method() == Method::Options {
+            return;
+        }
+        let route = route_name(request.uri().path().as_str()).to_string();
+        if let Err(retry_after) = self.0.check_request(request.client_ip(), &route) {
+            request.local_cache(|| Limited(Some(retry_after)));
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse("/rate-limited").unwrap());
+        }
+    }
+}
+
+// per user limit for routes with a `<token>` segment, add it as an argument to the routes that need it
+pub struct UserRateLimit;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserRateLimit {
+    type Error = RetryAfter;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = request_token(request);
+        let (limiter, server_arc) = match (request.rocket().state::<Arc<RateLimiter>>(), request.rocket().state::<Arc<Server>>()) {
+            (Some(limiter), Some(server_arc)) => (limiter, server_arc),
+            _ => return Outcome::Success(UserRateLimit),
+        };
+        let uid = token.and_then(|token| server_arc.tokens.lock().get(&token).cloned());
+        match uid.map(|uid| limiter.check_user(&uid.username)) {
+            Some(Err(retry_after)) => {
+                request.local_cache(|| Limited(Some(retry_after)));
+                Outcome::Failure((Status::TooManyRequests, retry_after))
+            }
+            _ => Outcome::Success(UserRateLimit),
+        }
+    }
+}
+
+#[get("/rate-limited")]
+pub fn rate_limited() -> Status {
+    Status::TooManyRequests
+}
+
+#[catch(429)]
+pub fn too_many_requests(request: &Request) -> TooManyRequests {
+    TooManyRequests(request.local_cache(|| Limited(None)).0.unwrap_or(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+    use super::{BucketKey, LimitRule, RateLimitConfig, RateLimiter};
+    use crate::clock::FakeClock;
+
+    fn ip() -> Option<IpAddr> {
+        Some("127.0.0.1".parse().unwrap())
+    }
+
+    fn limiter(config: RateLimitConfig) -> (RateLimiter, Arc<FakeClock>) {
+        let clock = FakeClock::new(1_000_000);
+        (RateLimiter::new(config, clock.clone()), clock)
+    }
+
+    fn config(per_ip: LimitRule, routes: &[(&str, LimitRule)]) -> RateLimitConfig {
+        RateLimitConfig {
+            rate_limit_per_ip: per_ip,
+            rate_limit_per_user: LimitRule { capacity: 100, per_seconds: 60 },
+            rate_limit_routes: routes.iter().map(|(route, rule)| (route.to_string(), *rule)).collect::<HashMap<_, _>>(),
+            login_free_attempts: 2,
+            login_account_free_attempts: 4,
+            login_lockout_seconds: 2,
+            login_lockout_max_seconds: 5,
+        }
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let (limiter, clock) = limiter(config(LimitRule { capacity: 2, per_seconds: 2 }, &[]));
+        assert!(limiter.check_request(ip(), "get-user").is_ok());
+        assert!(limiter.check_request(ip(), "get-user").is_ok());
+        assert!(limiter.check_request(ip(), "get-user").is_err());
+        clock.advance(1000);
+        assert!(limiter.check_request(ip(), "get-user").is_ok());
+        assert!(limiter.check_request(ip(), "get-user").is_err());
+        // never refills past capacity
+        clock.advance(60_000);
+        assert!(limiter.check_request(ip(), "get-user").is_ok());
+        assert!(limiter.check_request(ip(), "get-user").is_ok());
+        assert!(limiter.check_request(ip(), "get-user").is_err());
+    }
+
+    #[test]
+    fn retry_after_is_the_wait_for_the_next_token_rounded_up() {
+        let (limiter, clock) = limiter(config(LimitRule { capacity: 1, per_seconds: 10 }, &[]));
+        assert!(limiter.check_request(ip(), "get-user").is_ok());
+        assert_eq!(limiter.check_request(ip(), "get-user"), Err(10));
+        clock.advance(2500);
+        assert_eq!(limiter.check_request(ip(), "get-user"), Err(8));
+        clock.advance(7400);
+        assert_eq!(limiter.check_request(ip(), "get-user"), Err(1));
+        clock.advance(100);
+        assert!(limiter.check_request(ip(), "get-user").is_ok());
+    }
+
+    #[test]
+    fn retry_after_is_the_longest_wait_of_the_empty_buckets() {
+        let (limiter, _) = limiter(config(LimitRule { capacity: 1, per_seconds: 5 }, &[("login", LimitRule { capacity: 1, per_seconds: 60 })]));
+        assert!(limiter.check_request(ip(), "login").is_ok());
+        assert_eq!(limiter.check_request(ip(), "login"), Err(60));
+    }
+
+    #[test]
+    fn a_rejected_request_takes_from_no_bucket() {
+        let (limiter, _) = limiter(config(LimitRule { capacity: 3, per_seconds: 60 }, &[("login", LimitRule { capacity: 1, per_seconds: 60 })]));
+        let ip = ip().unwrap();
+        let checks = |user: &str| {
+            vec![
+                (BucketKey::Ip(ip), limiter.config.rate_limit_per_ip),
+                (BucketKey::Route("login".to_string(), ip), limiter.config.rate_limit_routes["login"]),
+                (BucketKey::User(user.to_string()), LimitRule { capacity: 1, per_seconds: 60 }),
+            ]
+        };
+        assert!(limiter.take(&checks("ann")).is_ok());
+        // the route and user buckets are empty, the ip bucket must not pay for these
+        assert!(limiter.take(&checks("ann")).is_err());
+        assert!(limiter.take(&checks("ben")).is_err());
+        assert!(limiter.take(&[(BucketKey::User("ann".to_string()), LimitRule { capacity: 1, per_seconds: 60 })]).is_err());
+        // the ip bucket still has the two tokens the first request left
+        assert!(limiter.check_request(Some(ip), "get-user").is_ok());
+        assert!(limiter.check_request(Some(ip), "get-user").is_ok());
+        assert!(limiter.check_request(Some(ip), "get-user").is_err());
+    }
+
+    #[test]
+    fn login_lockouts_double_up_to_the_maximum() {
+        // only the per ip lockout, the account wide one has its own test
+        let mut config = config(LimitRule { capacity: 100, per_seconds: 60 }, &[]);
+        config.login_account_free_attempts = 100;
+        let (limiter, clock) = limiter(config);
+        limiter.login_failed("ann", ip());
+        limiter.login_failed("ann", ip());
+        assert!(limiter.login_locked("ann", ip()).is_ok());
+        limiter.login_failed("ann", ip());
+        assert_eq!(limiter.login_locked("ann", ip()), Err(2));
+        clock.advance(2000);
+        assert!(limiter.login_locked("ann", ip()).is_ok());
+        limiter.login_failed("ann", ip());
+        assert_eq!(limiter.login_locked("ann", ip()), Err(4));
+        clock.advance(4000);
+        limiter.login_failed("ann", ip());
+        assert_eq!(limiter.login_locked("ann", ip()), Err(5));
+        clock.advance(5000);
+        limiter.login_failed("ann", ip());
+        assert_eq!(limiter.login_locked("ann", ip()), Err(5));
+        // usernames are matched case-insensitively, other ips aren't affected
+        assert_eq!(limiter.login_locked("ANN", ip()), Err(5));
+        assert!(limiter.login_locked("ann", Some("10.0.0.1".parse().unwrap())).is_ok());
+    }
+
+    #[test]
+    fn spreading_failures_over_ips_still_locks_the_account() {
+        let (limiter, clock) = limiter(config(LimitRule { capacity: 100, per_seconds: 60 }, &[]));
+        let from = |n: u8| Some(IpAddr::from([10, 0, 0, n]));
+        // one guess from each ip never uses up an ip's free attempts
+        for n in 0..4 {
+            limiter.login_failed("ann", from(n));
+            assert!(limiter.login_locked("ann", from(n)).is_ok());
+        }
+        limiter.login_failed("ann", from(4));
+        // every ip is locked out, including ones that never failed
+        assert_eq!(limiter.login_locked("ann", from(4)), Err(2));
+        assert_eq!(limiter.login_locked("Ann", from(99)), Err(2));
+        assert!(limiter.login_locked("ben", from(99)).is_ok());
+        clock.advance(2000);
+        assert!(limiter.login_locked("ann", from(5)).is_ok());
+        limiter.login_failed("ann", from(5));
+        assert_eq!(limiter.login_locked("ann", from(6)), Err(4));
+        clock.advance(4000);
+        limiter.login_failed("ann", from(6));
+        assert_eq!(limiter.login_locked("ann", from(7)), Err(5));
+        limiter.login_succeeded("ann", from(7));
+        assert!(limiter.login_locked("ann", from(8)).is_ok());
+    }
+
+    #[test]
+    fn a_successful_login_resets_the_failures() {
+        let (limiter, _) = limiter(config(LimitRule { capacity: 100, per_seconds: 60 }, &[]));
+        for _ in 0..4 {
+            limiter.login_failed("ann", ip());
+        }
+        assert!(limiter.login_locked("ann", ip()).is_err());
+        limiter.login_succeeded("ann", ip());
+        assert!(limiter.login_locked("ann", ip()).is_ok());
+        // the free attempts are back too
+        limiter.login_failed("ann", ip());
+        limiter.login_failed("ann", ip());
+        assert!(limiter.login_locked("ann", ip()).is_ok());
+        limiter.login_failed("ann", ip());
+        assert_eq!(limiter.login_locked("ann", ip()), Err(2));
+    }
+}
@@ -0,0 +1,149 @@
+use std::{collections::{BTreeMap, HashMap}, time::Instant};
+
+use rocket::data::ByteUnit;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{
+    Server,
+    expiry::forget_messages,
+    media::now_millis,
+    message::Message,
+    user_db::{DBEntry, TimeStamped, UserDB},
+};
+
+// the limit that removed an entry, used as the metrics label
+const AGE: &str = "age";
+const COUNT: &str = "count";
+const SIZE: &str = "size";
+
+#[derive(Deserialize, Clone)]
+pub struct RetentionConfig {
+    // seconds history is kept for, 0 keeps it forever
+    #[serde(default)]
+    pub retention_max_age: u64,
+    // newest entries kept in each chat, 0 for no limit
+    #[serde(default)]
+    pub retention_max_messages: usize,
+    // a user's oldest history is removed once all of it serializes to more than this, 0 for no limit
+    #[serde(default = "default_retention_max_user_size")]
+    pub retention_max_user_size: ByteUnit,
+    // seconds between compactions
+    #[serde(default = "default_retention_interval")]
+    pub retention_interval: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            retention_max_age: 0,
+            retention_max_messages: 0,
+            retention_max_user_size: default_retention_max_user_size(),
+            retention_interval: default_retention_interval(),
+        }
+    }
+}
+
+impl RetentionConfig {
+    pub fn enabled(&self) -> bool {
+        self.retention_max_age > 0 || self.retention_max_messages > 0 || self.retention_max_user_size.as_u64() > 0
+    }
+}
+
+fn default_retention_max_user_size() -> ByteUnit {
+    ByteUnit::Byte(0)
+}
+
+fn default_retention_interval() -> u64 {
+    60 * 60
+}
+
+// roughly what the entry adds to save/user_db.json
+fn entry_size(entry: &DBEntry) -> u64 {
+    serde_json::to_vec(entry).map(|json| json.len() as u64).unwrap_or(0)
+}
+
+// every (chatid, entry id) in one user's history that's past a limit, with the limit. age and count
+// are checked first, the size limit then removes the oldest of what they keep, across all chats
+fn past_limits(udb: &UserDB, config: &RetentionConfig, now: u128) -> HashMap<(u32, u32), &'static str> {
+    let mut past = HashMap::new();
+    let oldest_kept = now.saturating_sub(config.retention_max_age as u128 * 1000);
+    let max_size = config.retention_max_user_size.as_u64();
+    // (timestamp, chatid, id, size) of everything age and count keep
+    let mut kept = Vec::new();
+    for (chatid, entries) in &udb.messages.map {
+        for (i, id) in entries.timestamp_sorted.iter().enumerate() {
+            let entry = match entries.map.get(id) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let timestamp = entry.get_timestamp();
+            if config.retention_max_age > 0 && timestamp < oldest_kept {
+                past.insert((*chatid, *id), AGE);
+            } else if config.retention_max_messages > 0 && i >= config.retention_max_messages {
+                past.insert((*chatid, *id), COUNT);
+            } else if max_size > 0 {
+                kept.push((timestamp, *chatid, *id, entry_size(entry)));
+            }
+        }
+    }
+    let mut size: u64 = kept.iter().map(|(_, _, _, entry_size)| entry_size).sum();
+    kept.sort_by_key(|(timestamp, _, _, _)| *timestamp);
+    for (_, chatid, id, entry_size) in kept {
+        if size <= max_size {
+            break;
+        }
+        past.insert((chatid, id), SIZE);
+        size -= entry_size;
+    }
+    past
+}
+
+// removes everything past a limit from one user's history, returns the messages removed and how
+// many entries each limit removed
+fn compact_user(udb: &mut UserDB, config: &RetentionConfig, now: u128) -> (Vec<Message>, BTreeMap<&'static str, u64>) {
+    let mut by_chat: HashMap<u32, Vec<u32>> = HashMap::new();
+    let mut counts = BTreeMap::new();
+    for ((chatid, id), reason) in past_limits(udb, config, now) {
+        by_chat.entry(chatid).or_default().push(id);
+        *counts.entry(reason).or_insert(0) += 1;
+    }
+    let mut messages = Vec::new();
+    for (chatid, ids) in by_chat {
+        let mut entries = udb.messages.get(&chatid).unwrap().clone();
+        for id in ids {
+            if let Some(entry) = entries.remove(&id) {
+                let newest = udb.pruned.entry(chatid).or_insert(0);
+                *newest = (*newest).max(entry.get_timestamp());
+                messages.extend(entry.message);
+            }
+        }
+        udb.messages.update(chatid, entries);
+    }
+    (messages, counts)
+}
+
+// applies the retention limits to every user's history, returns how many entries were removed.
+// clients keep their own copies, so nothing is sent to their streams, get_chat_messages ends with a
+// `pruned` entry instead
+pub fn compact_history(server: &Server, config: &RetentionConfig) -> u64 {
+    if !config.enabled() {
+        return 0;
+    }
+    let started = Instant::now();
+    let now = now_millis();
+    let mut pruned = BTreeMap::new();
+    for (uid, shard) in server.user_db.entries() {
+        let (messages, counts) = compact_user(&mut shard.lock(), config, now);
+        forget_messages(server, &uid, &messages);
+        for (reason, count) in counts {
+            *pruned.entry(reason).or_insert(0) += count;
+        }
+    }
+    server.metrics.record_retention(&pruned, started.elapsed());
+    let total = pruned.values().sum();
+    if total > 0 {
+        info!(entries = total, "retention removed old history");
+    }
+    total
+}
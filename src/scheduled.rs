@@ -0,0 +1,409 @@
+use std::{sync::Arc, time::Duration};
+
+use rand::Rng;
+use rocket::{State, http::ContentType, serde::json::Json};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    Server,
+    actions::missing_devices_error,
+    admin::is_suspended,
+    clock::Clock,
+    contacts::user_by_id,
+    deliver_messages,
+    logging::RequestSpan,
+    message::EncryptedMessages,
+    rate_limit::UserRateLimit,
+    user::UserIdentifier,
+};
+
+#[derive(Deserialize, Clone)]
+pub struct ScheduleConfig {
+    // seconds between checks for due messages, so how late one can go out
+    #[serde(default = "default_schedule_check_interval")]
+    pub schedule_check_interval: u64,
+    // how far ahead a message can be scheduled, in seconds
+    #[serde(default = "default_schedule_max_ahead")]
+    pub schedule_max_ahead: u64,
+    // pending scheduled messages each user can have
+    #[serde(default = "default_schedule_max_pending")]
+    pub schedule_max_pending: usize,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            schedule_check_interval: default_schedule_check_interval(),
+            schedule_max_ahead: default_schedule_max_ahead(),
+            schedule_max_pending: default_schedule_max_pending(),
+        }
+    }
+}
+
+fn default_schedule_check_interval() -> u64 {
+    1
+}
+
+fn default_schedule_max_ahead() -> u64 {
+    365 * 24 * 60 * 60
+}
+
+fn default_schedule_max_pending() -> usize {
+    100
+}
+
+// a post-message payload held until `send_at`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ScheduledMessage {
+    pub id: u32,
+    // the sender's immutable user id, so renames don't orphan it
+    pub owner: u32,
+    pub send_at: u128,
+    pub created: u128,
+    pub messages: EncryptedMessages,
+    // what post-message answered the last time it was due. it isn't retried until it's edited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<serde_json::Value>,
+}
+
+impl ScheduledMessage {
+    // also clears the error, so a failed message is sent again when it's next due
+    fn apply(&mut self, edit: &ScheduleEdit) {
+        if let Some(send_at) = edit.send_at {
+            self.send_at = send_at;
+        }
+        if let Some(messages) = &edit.messages {
+            self.messages = messages.clone();
+        }
+        self.error = None;
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ScheduleMessage {
+    // unix millis
+    pub send_at: u128,
+    pub messages: EncryptedMessages,
+}
+
+#[derive(Deserialize)]
+pub struct ScheduleEdit {
+    #[serde(default)]
+    pub send_at: Option<u128>,
+    // replaces every copy, e.g. re-encrypted after a missing devices error
+    #[serde(default)]
+    pub messages: Option<EncryptedMessages>,
+}
+
+// sends scheduled messages once they're due. time comes from `clock`, so a fake one can drive it
+pub struct Scheduler {
+    config: ScheduleConfig,
+    clock: Arc<dyn Clock>,
+}
+
+impl Scheduler {
+    pub fn new(config: ScheduleConfig, clock: Arc<dyn Clock>) -> Self {
+        Self { config, clock }
+    }
+
+    pub fn check_interval(&self) -> Duration {
+        Duration::from_secs(self.config.schedule_check_interval.max(1))
+    }
+
+    fn check_send_at(&self, send_at: u128) -> Result<(), &'static str> {
+        let now = self.clock.now_millis();
+        if send_at <= now {
+            return Err("send_at is in the past");
+        }
+        if send_at > now + self.config.schedule_max_ahead as u128 * 1000 {
+            return Err("send_at is too far ahead");
+        }
+        Ok(())
+    }
+
+    // sends everything that's due, returns how many went out. the scheduled store is never locked
+    // while a message is delivered
+    pub fn run_due(&self, server: &Server) -> usize {
+        let now = self.clock.now_millis();
+        let due: Vec<ScheduledMessage> = {
+            let mut scheduled = server.scheduled.lock();
+            let ids: Vec<u32> = scheduled
+                .values()
+                .filter(|pending| pending.error.is_none() && pending.send_at <= now)
+                .map(|pending| pending.id)
+                .collect();
+            ids.iter().filter_map(|id| scheduled.remove(id)).collect()
+        };
+        let mut sent = 0;
+        for mut pending in due {
+            let owner = user_by_id(&server.users.lock(), pending.owner).map(|profile| UserIdentifier { username: profile.username.clone() });
+            let result = match owner {
+                // deleting an account drops its scheduled messages, so this is only a race with that
+                None => continue,
+                Some(owner) if is_suspended(server, &owner) => Err("{\"server\":\"suspended\"}".to_string()),
+                Some(owner) => deliver_messages(server, &owner, &pending.messages, Some(now)),
+            };
+            match result {
+                Ok(message_id) => {
+                    sent += 1;
+                    info!(scheduled = pending.id, message_id, "scheduled message sent");
+                }
+                Err(body) => {
+                    warn!(scheduled = pending.id, "scheduled message not sent: {body}");
+                    pending.error = serde_json::from_str(&body).ok();
+                    server.scheduled.lock().insert(pending.id, pending);
+                }
+            }
+        }
+        sent
+    }
+}
+
+fn reply(message: &str) -> (ContentType, String) {
+    (ContentType::JSON, format!("{{\"server\":\"{}\"}}", message))
+}
+
+fn token_user_id(server: &Server, token: u32) -> Option<u32> {
+    server.token_user(token).and_then(|uid| server.user_id(&uid))
+}
+
+#[post("/schedule-message/<token>", data = "<schedule>")]
+pub fn schedule_message(
+    token: u32,
+    schedule: Json<ScheduleMessage>,
+    _limit: UserRateLimit,
+    request: RequestSpan,
+    scheduler: &State<Arc<Scheduler>>,
+    server_arc: &State<Arc<Server>>,
+) -> (ContentType, String) {
+    let _entered = request.enter();
+    let server: &Server = server_arc;
+    let owner = match token_user_id(server, token) {
+        Some(owner) => owner,
+        None => return reply("invalid token"),
+    };
+    if let Err(e) = scheduler.check_send_at(schedule.send_at) {
+        return reply(e);
+    }
+    // checked again when it's sent, this just tells the client now
+    if let Some(body) = missing_devices_error(&schedule.messages, server) {
+        return (ContentType::JSON, body);
+    }
+    let id = rand::thread_rng().gen::<u32>();
+    let mut scheduled = server.scheduled.lock();
+    if scheduled.values().filter(|pending| pending.owner == owner).count() >= scheduler.config.schedule_max_pending {
+        return reply("too many scheduled messages");
+    }
+    scheduled.insert(id, ScheduledMessage {
+        id,
+        owner,
+        send_at: schedule.send_at,
+        created: scheduler.clock.now_millis(),
+        messages: schedule.messages.clone(),
+        error: None,
+    });
+    info!(scheduled = id, send_at = %schedule.send_at, "message scheduled");
+    (ContentType::JSON, format!("{{\"id\":{}, \"send_at\":{}}}", id, schedule.send_at))
+}
+
+// the caller's pending scheduled messages, soonest first
+#[get("/scheduled-messages/<token>")]
+pub fn get_scheduled_messages(token: u32, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let owner = match token_user_id(server, token) {
+        Some(owner) => owner,
+        None => return reply("invalid token"),
+    };
+    let mut pending: Vec<ScheduledMessage> = server.scheduled.lock().values().filter(|pending| pending.owner == owner).cloned().collect();
+    pending.sort_by_key(|pending| (pending.send_at, pending.id));
+    (ContentType::JSON, serde_json::to_string(&pending).expect("couldn't serialize scheduled messages"))
+}
+
+// editing clears a failed message's error, so it's sent again when it's next due
+#[post("/edit-scheduled-message/<token>/<id>", data = "<edit>")]
+pub fn edit_scheduled_message(
+    token: u32,
+    id: u32,
+    edit: Json<ScheduleEdit>,
+    request: RequestSpan,
+    scheduler: &State<Arc<Scheduler>>,
+    server_arc: &State<Arc<Server>>,
+) -> (ContentType, String) {
+    let _entered = request.enter();
+    let server: &Server = server_arc;
+    let owner = match token_user_id(server, token) {
+        Some(owner) => owner,
+        None => return reply("invalid token"),
+    };
+    if let Some(send_at) = edit.send_at {
+        if let Err(e) = scheduler.check_send_at(send_at) {
+            return reply(e);
+        }
+    }
+    if let Some(messages) = &edit.messages {
+        if let Some(body) = missing_devices_error(messages, server) {
+            return (ContentType::JSON, body);
+        }
+    }
+    let mut scheduled = server.scheduled.lock();
+    let pending = match scheduled.get_mut(&id) {
+        Some(pending) if pending.owner == owner => pending,
+        _ => return reply("no scheduled message found"),
+    };
+    pending.apply(&edit);
+    info!(scheduled = id, "scheduled message edited");
+    (ContentType::JSON, serde_json::to_string(pending).expect("couldn't serialize scheduled message"))
+}
+
+#[post("/cancel-scheduled-message/<token>/<id>")]
+pub fn cancel_scheduled_message(token: u32, id: u32, request: RequestSpan, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let _entered = request.enter();
+    let server: &Server = server_arc;
+    let owner = match token_user_id(server, token) {
+        Some(owner) => owner,
+        None => return reply("invalid token"),
+    };
+    let mut scheduled = server.scheduled.lock();
+    match scheduled.get(&id) {
+        Some(pending) if pending.owner == owner => {
+            scheduled.remove(&id);
+            info!(scheduled = id, "scheduled message cancelled");
+            reply("cancelled")
+        }
+        _ => reply("no scheduled message found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use super::{ScheduleConfig, ScheduleEdit, ScheduledMessage, Scheduler};
+    use crate::{
+        Server,
+        admin::{Account, Suspension},
+        clock::FakeClock,
+        message::{EncryptedMessages, SendMessage},
+        user::{UserIdentifier, UserProfile},
+    };
+
+    const NOW: u128 = 1_000_000;
+    const ANN: u32 = 1;
+    const BEN: u32 = 2;
+    const CHAT: u32 = 7;
+
+    fn scheduler() -> (Scheduler, Arc<FakeClock>) {
+        let clock = FakeClock::new(NOW);
+        let config = ScheduleConfig {
+            schedule_max_ahead: 60,
+            ..ScheduleConfig::default()
+        };
+        (Scheduler::new(config, clock.clone()), clock)
+    }
+
+    fn add_user(server: &Server, id: u32, username: &str) {
+        server.users.lock().insert(UserIdentifier { username: username.to_string() }, UserProfile {
+            id,
+            username: username.to_string(),
+            name: username.to_string(),
+            color: String::new(),
+            pfp: String::new(),
+            public_key: String::new(),
+            discoverable: true,
+        });
+    }
+
+    // ann and ben, with one message from ann to ben due at `send_at`
+    fn server_with_scheduled(id: u32, owner: u32, send_at: u128) -> Server {
+        let server = Server::new();
+        add_user(&server, ANN, "ann");
+        add_user(&server, BEN, "ben");
+        let message = SendMessage {
+            text: "hi".to_string(),
+            from_user: ANN,
+            chat: CHAT,
+            timestamp: NOW,
+            attachments: Vec::new(),
+        };
+        server.scheduled.lock().insert(id, ScheduledMessage {
+            id,
+            owner,
+            send_at,
+            created: NOW,
+            messages: EncryptedMessages {
+                encrypted_messages: HashMap::from([("ben".to_string(), message)]),
+                device_messages: HashMap::new(),
+            },
+            error: None,
+        });
+        server
+    }
+
+    fn delivered_to_ben(server: &Server) -> usize {
+        server
+            .user_db
+            .with(&UserIdentifier { username: "ben".to_string() }, |udb| udb.messages.get(&CHAT).map(|entries| entries.map.len()).unwrap_or(0))
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn send_at_has_to_be_ahead_but_not_too_far() {
+        let (scheduler, _clock) = scheduler();
+        assert_eq!(scheduler.check_send_at(NOW - 1), Err("send_at is in the past"));
+        assert_eq!(scheduler.check_send_at(NOW), Err("send_at is in the past"));
+        assert_eq!(scheduler.check_send_at(NOW + 1), Ok(()));
+        assert_eq!(scheduler.check_send_at(NOW + 60_000), Ok(()));
+        assert_eq!(scheduler.check_send_at(NOW + 60_001), Err("send_at is too far ahead"));
+    }
+
+    #[test]
+    fn due_messages_are_sent_exactly_once() {
+        let (scheduler, clock) = scheduler();
+        let server = server_with_scheduled(1, ANN, NOW + 1_000);
+        assert_eq!(scheduler.run_due(&server), 0);
+        assert_eq!(delivered_to_ben(&server), 0);
+        clock.advance(1_000);
+        assert_eq!(scheduler.run_due(&server), 1);
+        assert_eq!(scheduler.run_due(&server), 0);
+        clock.advance(60_000);
+        assert_eq!(scheduler.run_due(&server), 0);
+        assert_eq!(delivered_to_ben(&server), 1);
+        assert!(server.scheduled.lock().is_empty());
+    }
+
+    #[test]
+    fn a_failed_send_keeps_its_error_until_edited() {
+        let (scheduler, clock) = scheduler();
+        let server = server_with_scheduled(1, ANN, NOW + 1_000);
+        server.accounts.lock().insert(ANN, Account {
+            suspension: Some(Suspension { reason: "spam".to_string(), by: BEN, at: NOW }),
+            ..Account::default()
+        });
+        clock.advance(1_000);
+        assert_eq!(scheduler.run_due(&server), 0);
+        let error = server.scheduled.lock()[&1].error.clone();
+        assert_eq!(error, Some(serde_json::json!({"server": "suspended"})));
+        // lifting the suspension alone doesn't send it again
+        server.accounts.lock().remove(&ANN);
+        clock.advance(1_000);
+        assert_eq!(scheduler.run_due(&server), 0);
+        assert!(server.scheduled.lock()[&1].error.is_some());
+        assert_eq!(delivered_to_ben(&server), 0);
+        // an edit that changes nothing still clears the error
+        server.scheduled.lock().get_mut(&1).unwrap().apply(&ScheduleEdit { send_at: None, messages: None });
+        assert_eq!(scheduler.run_due(&server), 1);
+        assert_eq!(delivered_to_ben(&server), 1);
+        assert!(server.scheduled.lock().is_empty());
+    }
+
+    #[test]
+    fn messages_of_an_owner_that_no_longer_exists_are_dropped() {
+        let (scheduler, clock) = scheduler();
+        let server = server_with_scheduled(1, 99, NOW + 1_000);
+        clock.advance(1_000);
+        assert_eq!(scheduler.run_due(&server), 0);
+        assert!(server.scheduled.lock().is_empty());
+        assert_eq!(delivered_to_ben(&server), 0);
+    }
+}
@@ -0,0 +1,233 @@
+use std::{collections::{HashMap, HashSet}, sync::Arc};
+
+use rocket::{State, http::ContentType};
+use serde::Serialize;
+
+use crate::{Server, sessions::session_device, message::{Chat, Message}, user::UserIdentifier, user_db::{DBEntry, DBEntryType, DBMap, UserDB}};
+
+const SNIPPET_RADIUS: usize = 40;
+const DEFAULT_LIMIT: usize = 50;
+
+// per user inverted index over that user's own UserDB. metadata (chat, sender, timestamp) is kept for
+// every message but text is only indexed for chats that opted out of end to end encryption
+#[derive(Clone, Default)]
+pub struct SearchIndex {
+    postings: HashMap<String, HashSet<u32>>,
+    docs: HashMap<u32, IndexedMessage>,
+}
+
+#[derive(Clone)]
+struct IndexedMessage {
+    chat: u32,
+    from_user: String,
+    timestamp: u128,
+    terms: Vec<String>,
+}
+
+pub struct SearchQuery {
+    pub text: Option<String>,
+    pub chats: Vec<u32>,
+    pub from_users: Vec<String>,
+    pub after: Option<u128>,
+    pub before: Option<u128>,
+    pub limit: usize,
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    pub id: u32,
+    pub chat: u32,
+    pub from: String,
+    pub timestamp: u128,
+    pub snippet: Option<String>,
+}
+
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+impl SearchIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn build(udb: &UserDB, chats: &HashMap<u32, Chat>) -> Self {
+        let mut index = Self::new();
+        for (chatid, entries) in &udb.messages.map {
+            let searchable = chats.get(chatid).map(|chat| chat.searchable).unwrap_or(false);
+            index.index_chat(entries, searchable);
+        }
+        index
+    }
+
+    pub fn index_chat(&mut self, entries: &DBMap<DBEntry>, searchable: bool) {
+        for entry in entries.map.values() {
+            if entry.entry_type == DBEntryType::Message {
+                self.insert(entry.message.as_ref().unwrap(), searchable);
+            }
+        }
+    }
+
+    pub fn insert(&mut self, message: &Message, searchable: bool) {
+        self.remove(message.id);
+        // plaintext chats have the same text in every device copy, so any of them will do
+        let text = match message.device_copies.values().next() {
+            Some(copy) if message.text.is_empty() => &copy.text,
+            _ => &message.text,
+        };
+        let terms = if searchable { tokenize(text) } else { Vec::new() };
+        for term in &terms {
+            self.postings.entry(term.clone()).or_default().insert(message.id);
+        }
+        self.docs.insert(message.id, IndexedMessage {
+            chat: message.chat,
+            from_user: message.from_user.username.clone(),
+            timestamp: message.timestamp,
+            terms,
+        });
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        if let Some(doc) = self.docs.remove(&id) {
+            for term in doc.terms {
+                if let Some(ids) = self.postings.get_mut(&term) {
+                    ids.remove(&id);
+                    if ids.is_empty() {
+                        self.postings.remove(&term);
+                    }
+                }
+            }
+        }
+    }
+
+    // returns matching message ids with their chat, newest first
+    pub fn search(&self, query: &SearchQuery) -> Vec<(u32, u32)> {
+        let terms = query.text.as_deref().map(tokenize).unwrap_or_default();
+        let candidates: Vec<u32> = if terms.is_empty() {
+            self.docs.keys().copied().collect()
+        } else {
+            let mut matching: Option<HashSet<u32>> = None;
+            for term in &terms {
+                let ids = self.postings.get(term).cloned().unwrap_or_default();
+                matching = Some(match matching {
+                    Some(previous) => previous.intersection(&ids).copied().collect(),
+                    None => ids,
+                });
+            }
+            matching.unwrap_or_default().into_iter().collect()
+        };
+        let mut results: Vec<(u128, u32, u32)> = candidates
+            .into_iter()
+            .filter_map(|id| {
+                let doc = self.docs.get(&id)?;
+                if !query.chats.is_empty() && !query.chats.contains(&doc.chat) {
+                    return None;
+                }
+                if !query.from_users.is_empty() && !query.from_users.contains(&doc.from_user) {
+                    return None;
+                }
+                if query.after.map(|after| doc.timestamp <= after).unwrap_or(false) {
+                    return None;
+                }
+                if query.before.map(|before| doc.timestamp >= before).unwrap_or(false) {
+                    return None;
+                }
+                Some((doc.timestamp, id, doc.chat))
+            })
+            .collect();
+        results.sort_by_key(|result| std::cmp::Reverse(result.0));
+        results.truncate(query.limit);
+        results.into_iter().map(|(_, id, chat)| (id, chat)).collect()
+    }
+}
+
+pub fn snippet(text: &str, terms: &[String]) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let lowered: Vec<char> = text.to_lowercase().chars().collect();
+    let mut center = 0;
+    if lowered.len() == chars.len() {
+        let lowered_text: String = lowered.iter().collect();
+        if let Some(byte_pos) = terms.iter().filter_map(|term| lowered_text.find(term.as_str())).min() {
+            center = lowered_text[..byte_pos].chars().count();
+        }
+    }
+    let start = center.saturating_sub(SNIPPET_RADIUS);
+    let end = (center + SNIPPET_RADIUS).min(chars.len());
+    let mut snippet: String = chars[start..end].iter().collect();
+    if start > 0 {
+        snippet = format!("…{}", snippet);
+    }
+    if end < chars.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/search/<token>?<q>&<chat>&<from>&<after>&<before>&<limit>")]
+pub fn search_messages(
+    token: u32,
+    q: Option<String>,
+    chat: Vec<u32>,
+    from: Vec<String>,
+    after: Option<u128>,
+    before: Option<u128>,
+    limit: Option<usize>,
+    server_arc: &State<Arc<Server>>,
+) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let uid = match server.tokens.lock().get(&token) {
+        Some(uid) => uid.clone(),
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    let query = SearchQuery {
+        text: q,
+        chats: chat,
+        from_users: from,
+        after,
+        before,
+        limit: limit.unwrap_or(DEFAULT_LIMIT),
+    };
+    let matches = server.search_indexes.with(&uid, |index| index.search(&query)).unwrap_or_default();
+    let terms = query.text.as_deref().map(tokenize).unwrap_or_default();
+    let device = session_device(server, token);
+    // chats lock before user dbs, so look up which chats allow snippets first
+    let mut searchable_chats = HashMap::new();
+    for (_, chatid) in &matches {
+        searchable_chats
+            .entry(*chatid)
+            .or_insert_with(|| server.chats.with(chatid, |chat| chat.searchable).unwrap_or(false));
+    }
+    let shard = match server.user_db.get(&uid) {
+        Some(shard) => shard,
+        None => return (ContentType::JSON, "[]".into()),
+    };
+    let udb = shard.lock();
+    let mut results = Vec::new();
+    for (id, chatid) in matches {
+        let message = udb
+            .messages
+            .get(&chatid)
+            .and_then(|entries| entries.get(&id))
+            .and_then(|entry| entry.message.as_ref());
+        if let Some(message) = message {
+            let searchable = searchable_chats[&chatid];
+            results.push(SearchResult {
+                id,
+                chat: chatid,
+                from: message.from_user.username.clone(),
+                timestamp: message.timestamp,
+                snippet: if searchable { Some(snippet(&message.for_device(device).text, &terms)) } else { None },
+            });
+        }
+    }
+    (ContentType::JSON, serde_json::to_string(&results).expect("couldn't serialize search results"))
+}
+
+pub fn index_message(message: &Message, to_user: &UserIdentifier, server: &Server) {
+    let searchable = server.chats.with(&message.chat, |chat| chat.searchable).unwrap_or(false);
+    server.search_indexes.with_or_insert(to_user, SearchIndex::default, |index| index.insert(message, searchable));
+}
@@ -39,6 +39,11 @@ pub enum SendableType {
     Read,
     Banner,
     Reaction,
+    KeyChanged,
+    Reconnect,
+    Announcement,
+    Deleted,
+    Pruned,
 }
 
 impl SendableType {
@@ -48,6 +53,11 @@ impl SendableType {
             SendableType::Read => "read".to_string(),
             SendableType::Banner => "banner".to_string(),
             SendableType::Reaction => "reaction".to_string(),
+            SendableType::KeyChanged => "key_changed".to_string(),
+            SendableType::Reconnect => "reconnect".to_string(),
+            SendableType::Announcement => "announcement".to_string(),
+            SendableType::Deleted => "deleted".to_string(),
+            SendableType::Pruned => "pruned".to_string(),
         }
     }
 }
@@ -79,4 +89,66 @@ pub fn reaction(emoji: String, username: String, messageid: u32, chatid: u32) ->
     let timestamp = since_the_epoch.as_millis();
     let sendable = Sendable::new(SendableType::Reaction, format!("{{\"emoji\":\"{}\", \"message\":{}, \"from\":\"{}\", \"chat\":{}}}", emoji, messageid, username, chatid), Some(timestamp));
     sendable
-}
\ No newline at end of file
+}
+pub fn read_batch(status: String, username: String, messageids: &Vec<u32>, chatid: u32) -> Sendable {
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let timestamp = since_the_epoch.as_millis();
+    // "message" holds the newest id so clients that only understand single reads still advance
+    let newest = messageids.last().copied().unwrap_or(0);
+    let ids = serde_json::to_string(messageids).expect("couldn't serialize message ids");
+    let sendable = Sendable::new(SendableType::Read, format!("{{\"status\":\"{}\", \"message\":{{\"id\":{}, \"chat\":{}}}, \"messages\":{{\"ids\":{}, \"chat\":{}}}, \"from\":\"{}\"}}", status, newest, chatid, ids, chatid, username), Some(timestamp));
+    sendable
+}
+
+pub fn key_changed(username: String, device_id: u32, fingerprint: String, chatid: u32) -> Sendable {
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let timestamp = since_the_epoch.as_millis();
+    let sendable = Sendable::new(SendableType::KeyChanged, format!("{{\"user\":\"{}\", \"device\":{}, \"fingerprint\":\"{}\", \"chat\":{}}}", username, device_id, fingerprint, chatid), Some(timestamp));
+    sendable
+}
+
+// the server is going away, clients should open a new stream after `retry_after` milliseconds
+pub fn reconnect(retry_after: u64) -> Sendable {
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let timestamp = since_the_epoch.as_millis();
+    let sendable = Sendable::new(SendableType::Reconnect, format!("{{\"retry_after\":{}}}", retry_after), Some(timestamp));
+    sendable
+}
+
+// a server-wide banner from an operator, not tied to any chat
+pub fn announcement(text: &str, id: u32) -> Sendable {
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let timestamp = since_the_epoch.as_millis();
+    let text = serde_json::to_string(text).expect("couldn't serialize announcement");
+    let sendable = Sendable::new(SendableType::Announcement, format!("{{\"text\":{}, \"id\":{}}}", text, id), Some(timestamp));
+    sendable
+}
+
+// messages removed from the server, clients should drop their copies too
+pub fn deleted(messageids: &Vec<u32>, chatid: u32) -> Sendable {
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let timestamp = since_the_epoch.as_millis();
+    let ids = serde_json::to_string(messageids).expect("couldn't serialize message ids");
+    let sendable = Sendable::new(SendableType::Deleted, format!("{{\"chat\":{}, \"messages\":{}}}", chatid, ids), Some(timestamp));
+    sendable
+}
+
+// the server no longer has history in `chatid` from `before` back, it was removed by retention
+pub fn pruned(chatid: u32, before: u128) -> Sendable {
+    Sendable::new(SendableType::Pruned, format!("{{\"chat\":{}, \"before\":{}}}", chatid, before), Some(before))
+}
@@ -0,0 +1,294 @@
+use std::{collections::HashMap, sync::{mpsc::Sender, Arc}};
+
+use rand::Rng;
+use rocket::{Request, Response, State, fairing::{Fairing, Info, Kind}, http::ContentType, request::{FromRequest, Outcome}};
+use serde::{Deserialize, Serialize};
+
+use crate::{Server, audit::{AuditAction, AuditEntry}, media::now_millis, sendables::Sendable, user::UserIdentifier};
+
+// what the server remembers about a token beyond which user it belongs to
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct Session {
+    // the registered device this token was issued to, if the client has registered one
+    pub device: Option<u32>,
+    // tokens are secrets, this is what the session is called when listing or revoking it
+    #[serde(default)]
+    pub id: u32,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub created: u128,
+    #[serde(default)]
+    pub last_active: u128,
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    #[serde(default)]
+    pub ip: Option<String>,
+}
+
+// one open SSE or websocket connection
+#[derive(Clone)]
+pub struct EventSender {
+    pub token: u32,
+    pub device: Option<u32>,
+    pub sender: Sender<Sendable>,
+}
+
+// who is on the other end of a request, recorded on the session it uses
+#[derive(Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientInfo {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ClientInfo {
+            user_agent: request.headers().get_one("User-Agent").map(|user_agent| user_agent.to_string()),
+            ip: request.client_ip().map(|ip| ip.to_string()),
+        })
+    }
+}
+
+// None if the account is gone. the password is checked under the tokens lock, which delete_account
+// holds while it removes both, so no session can outlive the account
+pub fn create_session(server: &Server, uid: UserIdentifier, device: Option<u32>, client: &ClientInfo) -> Option<u32> {
+    let mut tokens = server.tokens.lock();
+    if !server.passwords.lock().contains_key(&uid) {
+        return None;
+    }
+    let mut rng = rand::thread_rng();
+    let mut token = rng.gen::<u32>();
+    while tokens.contains_key(&token) {
+        token = rng.gen::<u32>();
+    }
+    tokens.insert(token, uid.clone());
+    let now = now_millis();
+    let session_id = rng.gen();
+    server.sessions.lock().insert(token, Session {
+        device,
+        id: session_id,
+        name: None,
+        created: now,
+        last_active: now,
+        user_agent: client.user_agent.clone(),
+        ip: client.ip.clone(),
+    });
+    drop(tokens);
+    let user_id = server.user_id(&uid).unwrap_or(0);
+    server.audit.record(AuditEntry::new(AuditAction::TokenCreated, user_id).user(user_id).target(session_id).ip(client));
+    Some(token)
+}
+
+// removes the token and closes every stream opened with it, dropping the sender ends the stream loops
+pub fn end_session(server: &Server, token: u32) {
+    let uid = server.tokens.lock().remove(&token);
+    server.sessions.lock().remove(&token);
+    if let Some(uid) = uid {
+        if let Some(senders) = server.event_stream_senders.lock().get_mut(&uid) {
+            senders.retain(|sender| sender.token != token);
+        }
+    }
+}
+
+// ends every session `uid` has, returns how many there were
+pub fn end_user_sessions(server: &Server, uid: &UserIdentifier) -> usize {
+    let tokens: Vec<u32> = server
+        .tokens
+        .lock()
+        .iter()
+        .filter(|(_, token_uid)| *token_uid == uid)
+        .map(|(token, _)| *token)
+        .collect();
+    for token in &tokens {
+        end_session(server, *token);
+    }
+    tokens.len()
+}
+
+pub fn session_device(server: &Server, token: u32) -> Option<u32> {
+    server.sessions.lock().get(&token).and_then(|session| session.device)
+}
+
+pub fn bind_session_device(server: &Server, token: u32, device: u32) {
+    server.sessions.lock().entry(token).or_default().device = Some(device);
+}
+
+pub fn touch_session(server: &Server, token: u32, client: &ClientInfo) {
+    if !server.tokens.lock().contains_key(&token) {
+        return;
+    }
+    if let Some(session) = server.sessions.lock().get_mut(&token) {
+        session.last_active = now_millis();
+        if client.user_agent.is_some() {
+            session.user_agent = client.user_agent.clone();
+        }
+        if client.ip.is_some() {
+            session.ip = client.ip.clone();
+        }
+    }
+}
+
+// tokens from saves made before sessions had ids get one, so they can still be listed and revoked
+pub fn fill_missing_sessions(tokens: &HashMap<u32, UserIdentifier>, sessions: &mut HashMap<u32, Session>) {
+    let mut rng = rand::thread_rng();
+    for token in tokens.keys() {
+        let session = sessions.entry(*token).or_default();
+        if session.id == 0 {
+            session.id = rng.gen();
+        }
+    }
+    sessions.retain(|token, _| tokens.contains_key(token));
+}
+
+pub fn add_event_sender(server: &Server, uid: &UserIdentifier, token: u32, sender: Sender<Sendable>) {
+    let device = session_device(server, token);
+    server
+        .event_stream_senders
+        .lock()
+        .entry(uid.clone())
+        .or_default()
+        .push(EventSender { token, device, sender });
+}
+
+pub fn session_token(server: &Server, uid: &UserIdentifier, session_id: u32) -> Option<u32> {
+    let tokens = server.tokens.lock();
+    server
+        .sessions
+        .lock()
+        .iter()
+        .find(|(token, session)| session.id == session_id && tokens.get(token) == Some(uid))
+        .map(|(token, _)| *token)
+}
+
+// the token from the matched route's `<token>` segment, if it has one
+pub fn request_token(request: &Request<'_>) -> Option<u32> {
+    let route = request.route()?;
+    let position = route.uri.path().trim_start_matches('/').split('/').position(|segment| segment == "<token>")?;
+    request.routed_segment(position)?.parse::<u32>().ok()
+}
+
+// marks the session used by any route with a `<token>` segment as active
+pub struct SessionActivity;
+
+#[rocket::async_trait]
+impl Fairing for SessionActivity {
+    fn info(&self) -> Info {
+        Info {
+            name: "Track session activity",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, _: &mut Response<'r>) {
+        let token = match request_token(request) {
+            Some(token) => token,
+            None => return,
+        };
+        if let Some(server_arc) = request.rocket().state::<Arc<Server>>() {
+            let client = ClientInfo {
+                user_agent: request.headers().get_one("User-Agent").map(|user_agent| user_agent.to_string()),
+                ip: request.client_ip().map(|ip| ip.to_string()),
+            };
+            touch_session(server_arc, token, &client);
+        }
+    }
+}
+
+#[get("/sessions/<token>")]
+pub fn list_sessions(token: u32, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let uid = match server.tokens.lock().get(&token) {
+        Some(uid) => uid.clone(),
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    let tokens = server.tokens.lock();
+    let sessions = server.sessions.lock();
+    let keys = server.keys.lock();
+    let mut listed = Vec::new();
+    for (session_token, session) in sessions.iter() {
+        if tokens.get(session_token) != Some(&uid) {
+            continue;
+        }
+        let device_name = session
+            .device
+            .and_then(|device| keys.get(&uid).and_then(|user_keys| user_keys.device(device)))
+            .map(|device| device.name.clone());
+        listed.push(format!(
+            "{{\"id\":{}, \"name\":{}, \"device\":{}, \"device_name\":{}, \"created\":{}, \"last_active\":{}, \"user_agent\":{}, \"ip\":{}, \"current\":{}}}",
+            session.id,
+            serde_json::to_string(&session.name).unwrap(),
+            serde_json::to_string(&session.device).unwrap(),
+            serde_json::to_string(&device_name).unwrap(),
+            session.created,
+            session.last_active,
+            serde_json::to_string(&session.user_agent).unwrap(),
+            serde_json::to_string(&session.ip).unwrap(),
+            *session_token == token
+        ));
+    }
+    (ContentType::JSON, format!("[{}]", listed.join(",")))
+}
+
+#[post("/sessions/rename/<token>/<session_id>", data = "<name>")]
+pub fn rename_session(token: u32, session_id: u32, name: String, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let uid = match server.tokens.lock().get(&token) {
+        Some(uid) => uid.clone(),
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    let session_token = match session_token(server, &uid, session_id) {
+        Some(session_token) => session_token,
+        None => return (ContentType::JSON, "{\"server\":\"no such session\"}".into()),
+    };
+    let name = name.trim().to_string();
+    if let Some(session) = server.sessions.lock().get_mut(&session_token) {
+        session.name = if name.is_empty() { None } else { Some(name) };
+    }
+    (ContentType::JSON, "{\"server\":\"renamed\"}".into())
+}
+
+#[post("/sessions/revoke/<token>/<session_id>")]
+pub fn revoke_session(token: u32, session_id: u32, client: ClientInfo, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let uid = match server.tokens.lock().get(&token) {
+        Some(uid) => uid.clone(),
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    match session_token(server, &uid, session_id) {
+        Some(session_token) => {
+            end_session(server, session_token);
+            let user_id = server.user_id(&uid).unwrap_or(0);
+            server.audit.record(AuditEntry::new(AuditAction::SessionRevoked, user_id).user(user_id).target(session_id).ip(&client));
+            (ContentType::JSON, "{\"server\":\"revoked\"}".into())
+        }
+        None => (ContentType::JSON, "{\"server\":\"no such session\"}".into()),
+    }
+}
+
+#[post("/sessions/revoke-others/<token>")]
+pub fn revoke_other_sessions(token: u32, client: ClientInfo, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    let uid = match server.tokens.lock().get(&token) {
+        Some(uid) => uid.clone(),
+        None => return (ContentType::JSON, "{\"server\":\"invalid token\"}".into()),
+    };
+    let others: Vec<u32> = server
+        .tokens
+        .lock()
+        .iter()
+        .filter(|(other, other_uid)| **other != token && **other_uid == uid)
+        .map(|(other, _)| *other)
+        .collect();
+    for other in &others {
+        end_session(server, *other);
+    }
+    if !others.is_empty() {
+        let user_id = server.user_id(&uid).unwrap_or(0);
+        server.audit.record(AuditEntry::new(AuditAction::SessionRevoked, user_id).user(user_id).ip(&client).detail(format!("{} other sessions", others.len())));
+    }
+    (ContentType::JSON, format!("{{\"revoked\":{}}}", others.len()))
+}
@@ -0,0 +1,224 @@
+use std::{collections::HashMap, hash::Hash, sync::Arc};
+
+use parking_lot::{Mutex, RwLock};
+
+// a map where every value has its own lock. the map itself is only write locked to add or remove
+// entries, so work on one chat or one user's db never waits on another's
+pub struct Sharded<K, V> {
+    shards: RwLock<HashMap<K, Arc<Mutex<V>>>>,
+}
+
+impl<K: Eq + Hash + Clone, V> Default for Sharded<K, V> {
+    fn default() -> Self {
+        Self { shards: RwLock::new(HashMap::new()) }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V> Sharded<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_map(map: HashMap<K, V>) -> Self {
+        Self {
+            shards: RwLock::new(map.into_iter().map(|(key, value)| (key, Arc::new(Mutex::new(value)))).collect()),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<Arc<Mutex<V>>> {
+        self.shards.read().get(key).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.read().len()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.shards.read().contains_key(key)
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        self.shards.write().insert(key, Arc::new(Mutex::new(value)));
+    }
+
+    pub fn remove(&self, key: &K) -> Option<Arc<Mutex<V>>> {
+        self.shards.write().remove(key)
+    }
+
+    // moves the entry, keeping its lock, so anyone holding it sees the same value
+    pub fn rekey(&self, old: &K, new: K) {
+        let mut shards = self.shards.write();
+        if let Some(shard) = shards.remove(old) {
+            shards.insert(new, shard);
+        }
+    }
+
+    // a snapshot of the entries, the map isn't locked while the caller goes through them
+    pub fn entries(&self) -> Vec<(K, Arc<Mutex<V>>)> {
+        self.shards.read().iter().map(|(key, shard)| (key.clone(), shard.clone())).collect()
+    }
+
+    // runs `f` with only this entry locked
+    pub fn with<R>(&self, key: &K, f: impl FnOnce(&mut V) -> R) -> Option<R> {
+        let shard = self.get(key)?;
+        let mut value = shard.lock();
+        Some(f(&mut value))
+    }
+
+    pub fn get_or_insert(&self, key: &K, default: impl FnOnce() -> V) -> Arc<Mutex<V>> {
+        match self.get(key) {
+            Some(shard) => shard,
+            None => self.shards.write().entry(key.clone()).or_insert_with(|| Arc::new(Mutex::new(default()))).clone(),
+        }
+    }
+
+    pub fn with_or_insert<R>(&self, key: &K, default: impl FnOnce() -> V, f: impl FnOnce(&mut V) -> R) -> R {
+        let shard = self.get_or_insert(key, default);
+        let mut value = shard.lock();
+        f(&mut value)
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Sharded<K, V> {
+    // every entry is locked on its own, so this is consistent per entry, not across entries
+    pub fn snapshot(&self) -> HashMap<K, V> {
+        self.entries().into_iter().map(|(key, shard)| (key, shard.lock().clone())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{Arc, Barrier, mpsc}, thread, time::Duration};
+
+    use super::Sharded;
+    use crate::{user::UserIdentifier, user_db::UserDB};
+
+    // runs `work` on another thread, failing if it hasn't finished in a few seconds
+    fn finishes(work: impl FnOnce() + Send + 'static) {
+        let (done, finished) = mpsc::channel();
+        thread::spawn(move || {
+            work();
+            let _ = done.send(());
+        });
+        assert!(finished.recv_timeout(Duration::from_secs(5)).is_ok(), "blocked on another shard's lock");
+    }
+
+    #[test]
+    fn a_held_chat_doesnt_block_other_chats() {
+        let chats: Arc<Sharded<u32, Vec<String>>> = Arc::new(Sharded::new());
+        chats.insert(1, Vec::new());
+        chats.insert(2, Vec::new());
+        let busy = chats.get(&1).unwrap();
+        let _held = busy.lock();
+        let others = chats.clone();
+        finishes(move || {
+            others.with(&2, |chat| chat.push("hi".to_string()));
+            others.with_or_insert(&3, Vec::new, |chat| chat.push("new".to_string()));
+            others.insert(4, Vec::new());
+            assert_eq!(others.len(), 4);
+            assert_eq!(others.entries().len(), 4);
+        });
+        assert_eq!(chats.with(&2, |chat| chat.len()), Some(1));
+    }
+
+    #[test]
+    fn posts_to_many_chats_from_many_threads_dont_wait_on_each_other() {
+        const CHATS: u32 = 16;
+        const POSTS: usize = 500;
+        let chats: Arc<Sharded<u32, Vec<String>>> = Arc::new(Sharded::new());
+        for chatid in 0..=CHATS {
+            chats.insert(chatid, Vec::new());
+        }
+        let busy = chats.get(&0).unwrap();
+        let held = busy.lock();
+        let posting = chats.clone();
+        finishes(move || {
+            // every thread waits inside its own chat's lock until all of them are in, which only
+            // works if no chat's lock waits on another's
+            let all_in = Arc::new(Barrier::new(CHATS as usize));
+            let posters: Vec<_> = (1..=CHATS)
+                .map(|chatid| {
+                    let chats = posting.clone();
+                    let all_in = all_in.clone();
+                    thread::spawn(move || {
+                        chats.with(&chatid, |chat| {
+                            all_in.wait();
+                            chat.push("first".to_string());
+                        });
+                        for post in 1..POSTS {
+                            chats.with(&chatid, |chat| chat.push(format!("post {post}")));
+                        }
+                    })
+                })
+                .collect();
+            for poster in posters {
+                poster.join().unwrap();
+            }
+        });
+        assert!(held.is_empty());
+        drop(held);
+        for chatid in 1..=CHATS {
+            assert_eq!(chats.with(&chatid, |chat| chat.len()), Some(POSTS));
+        }
+        chats.with(&0, |chat| chat.push("after".to_string()));
+        assert_eq!(chats.with(&0, |chat| chat.len()), Some(1));
+    }
+
+    #[test]
+    fn a_held_user_db_doesnt_block_other_user_dbs() {
+        let ann = UserIdentifier { username: "ann".to_string() };
+        let ben = UserIdentifier { username: "ben".to_string() };
+        let user_db: Arc<Sharded<UserIdentifier, UserDB>> = Arc::new(Sharded::new());
+        user_db.insert(ann.clone(), UserDB::new());
+        user_db.insert(ben.clone(), UserDB::new());
+        let busy = user_db.get(&ann).unwrap();
+        let _held = busy.lock();
+        let others = user_db.clone();
+        finishes(move || {
+            others.with(&ben, |udb| udb.read_cursors.insert(1, 10));
+            others.rekey(&ben, UserIdentifier { username: "benny".to_string() });
+        });
+        assert!(user_db.contains_key(&UserIdentifier { username: "benny".to_string() }));
+    }
+
+    #[test]
+    fn rekey_keeps_the_same_entry() {
+        let map: Sharded<String, u32> = Sharded::new();
+        map.insert("old".to_string(), 1);
+        let held = map.get(&"old".to_string()).unwrap();
+        map.rekey(&"old".to_string(), "new".to_string());
+        *held.lock() += 1;
+        assert_eq!(map.with(&"new".to_string(), |value| *value), Some(2));
+        assert!(map.get(&"old".to_string()).is_none());
+    }
+
+    #[test]
+    fn with_or_insert_racing_rekey_and_remove_loses_nothing() {
+        const WRITES: u64 = 20_000;
+        let map: Arc<Sharded<String, u64>> = Arc::new(Sharded::new());
+        let writer_map = map.clone();
+        let writer = thread::spawn(move || {
+            for _ in 0..WRITES {
+                writer_map.with_or_insert(&"old".to_string(), || 0, |value| *value += 1);
+            }
+        });
+        let mover_map = map.clone();
+        let mover = thread::spawn(move || {
+            let mut removed = Vec::new();
+            for _ in 0..WRITES / 4 {
+                mover_map.rekey(&"old".to_string(), "new".to_string());
+                if let Some(shard) = mover_map.remove(&"new".to_string()) {
+                    removed.push(shard);
+                }
+                thread::yield_now();
+            }
+            removed
+        });
+        writer.join().unwrap();
+        let removed = mover.join().unwrap();
+        // every write landed in exactly one entry, whether it's still in the map or was removed
+        let in_map: u64 = map.snapshot().values().sum();
+        let in_removed: u64 = removed.iter().map(|shard| *shard.lock()).sum();
+        assert_eq!(in_map + in_removed, WRITES);
+    }
+}
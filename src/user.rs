@@ -32,16 +32,26 @@ pub fn username_map_into<T>(input_map: HashMap<String, T>) -> HashMap<UserIdenti
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UserProfile {
+    // never changes, even when the username does
+    #[serde(default)]
+    pub id: u32,
     pub username: String,
     pub name: String,
     pub color: String,
     pub pfp: String,
     pub public_key: String,
+    // whether the user shows up in directory search, an exact username still finds them either way
+    #[serde(default = "default_discoverable")]
+    pub discoverable: bool,
+}
+
+fn default_discoverable() -> bool {
+    true
 }
 
 impl UserProfile {
     pub fn dummy(username: String) -> UserProfile {
-        UserProfile { username, name: "".to_string(), color: "".to_string(), pfp:"".to_string(), public_key:"".to_string() }
+        UserProfile { id: 0, username, name: "".to_string(), color: "".to_string(), pfp:"".to_string(), public_key:"".to_string(), discoverable: false }
     }
 }
 
@@ -50,16 +60,23 @@ pub struct CreateUser {
     pub name: String,
     pub color: String,
     pub public_key: String,
+    // base64 ed25519 key the first device signs later key rotations with
+    #[serde(default)]
+    pub identity_key: Option<String>,
+    #[serde(default = "default_discoverable")]
+    pub discoverable: bool,
 }
 
 impl CreateUser {
-    pub fn to_user_profile(&self, username: String, pfp: String) -> UserProfile {
+    pub fn to_user_profile(&self, id: u32, username: String, pfp: String) -> UserProfile {
         UserProfile {
+            id,
             username,
             name: self.name.clone(),
             color: self.color.clone(),
             pfp: pfp,
             public_key: self.public_key.clone(),
+            discoverable: self.discoverable,
         }
     }
 }
\ No newline at end of file
@@ -1,18 +1,54 @@
-use std::{collections::HashMap, sync::{Mutex, Arc}};
+use std::{cmp::Reverse, collections::{HashMap, HashSet}, sync::Arc};
 
 use rocket::{State, http::ContentType};
 use serde::{Deserialize, Serialize};
-use crate::{Server, message::Message, sendables::{Sendable, SendableType}};
+use crate::{Server, message::Message, sessions::session_device, sendables::{Sendable, SendableType, pruned}};
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct UserDB {
     pub messages: DBMap<DBMap<DBEntry>>,
+    // timestamp of the newest message this user has read in each chat
+    #[serde(default)]
+    pub read_cursors: HashMap<u32, u128>,
+    // timestamp of the newest entry retention removed from each chat
+    #[serde(default)]
+    pub pruned: HashMap<u32, u128>,
 }
 
 impl UserDB {
     pub fn new() -> Self {
         Self {
             messages: DBMap::new(),
+            read_cursors: HashMap::new(),
+            pruned: HashMap::new(),
+        }
+    }
+
+    // adds `entry` to a chat this user already has history for
+    pub fn add_to_chat(&mut self, chatid: u32, id: u32, entry: DBEntry) {
+        if let Some(entries) = self.messages.get(&chatid) {
+            let mut entries = entries.clone();
+            entries.insert(id, entry);
+            self.messages.update(chatid, entries);
+        }
+    }
+
+    // runs `edit` on this user's copy of a message, if they have it
+    pub fn edit_message(&mut self, chatid: u32, messageid: u32, edit: impl FnMut(&mut Message)) {
+        self.edit_messages(chatid, &[messageid], edit);
+    }
+
+    pub fn edit_messages(&mut self, chatid: u32, messageids: &[u32], mut edit: impl FnMut(&mut Message)) {
+        if let Some(entries) = self.messages.get(&chatid) {
+            let mut entries = entries.clone();
+            for messageid in messageids {
+                if let Some(entry) = entries.map.get_mut(messageid) {
+                    if entry.entry_type == DBEntryType::Message {
+                        edit(entry.message.as_mut().unwrap());
+                    }
+                }
+            }
+            self.messages.update(chatid, entries);
         }
     }
 }
@@ -65,6 +101,47 @@ impl<T: TimeStamped> DBMap<T> {
         }
         self.insert(key, new_entry);
     }
+
+    pub fn remove(&mut self, key: &u32) -> Option<T> {
+        self.timestamp_sorted.retain(|x| x != key);
+        self.map.remove(key)
+    }
+
+    // what's wrong with `timestamp_sorted`, empty when it lists every key once, newest first
+    pub fn index_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut seen = HashSet::new();
+        for key in &self.timestamp_sorted {
+            if !self.map.contains_key(key) {
+                problems.push(format!("{key} is indexed but missing"));
+            } else if !seen.insert(*key) {
+                problems.push(format!("{key} is indexed more than once"));
+            }
+        }
+        for key in self.map.keys() {
+            if !seen.contains(key) {
+                problems.push(format!("{key} isn't indexed"));
+            }
+        }
+        let timestamps: Vec<u128> = self
+            .timestamp_sorted
+            .iter()
+            .filter_map(|key| self.map.get(key))
+            .map(|entry| entry.get_timestamp())
+            .collect();
+        if timestamps.windows(2).any(|pair| pair[0] < pair[1]) {
+            problems.push("out of order".to_string());
+        }
+        problems
+    }
+
+    // rebuilds `timestamp_sorted` from the map, ties keep their current relative order
+    pub fn rebuild_index(&mut self) {
+        let position: HashMap<u32, usize> = self.timestamp_sorted.iter().enumerate().rev().map(|(i, key)| (*key, i)).collect();
+        let mut keys: Vec<u32> = self.map.keys().copied().collect();
+        keys.sort_by_key(|key| (Reverse(self.map[key].get_timestamp()), position.get(key).copied().unwrap_or(usize::MAX), *key));
+        self.timestamp_sorted = keys;
+    }
 }
 
 impl<T: TimeStamped> TimeStamped for DBMap<T> {
@@ -121,18 +198,20 @@ pub trait TimeStamped {
 }
 
 #[get("/db/message/<token>/<chat>/<message>")]
-pub fn get_message(token: u32, chat: u32, message: u32, server_arc: &State<Arc<Mutex<Server>>>) -> (ContentType, String) {
-    let server = server_arc.lock().unwrap();
-    if server.tokens.lock().unwrap().contains_key(&token) {
-        let tokens = server.tokens.lock().unwrap();
-        let user_db = server.user_db.lock().unwrap();
-        let uid = tokens.get(&token).unwrap();
-        let udb = user_db.get(uid).unwrap();
+pub fn get_message(token: u32, chat: u32, message: u32, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    if let Some(uid) = server.token_user(token) {
+        let device = session_device(server, token);
+        let shard = match server.user_db.get(&uid) {
+            Some(shard) => shard,
+            None => return (ContentType::JSON, "{\"server\":\"no chat found\"}".into()),
+        };
+        let udb = shard.lock();
         if udb.messages.contains_key(&chat) {
             if udb.messages.get(&chat).unwrap().contains_key(&message) {
                 let entry = udb.messages.get(&chat).unwrap().get(&message).unwrap();
                 let serialized = match entry.entry_type {
-                    DBEntryType::Message => serde_json::ser::to_string(entry.message.as_ref().unwrap()).expect("couldn't serialize message"),
+                    DBEntryType::Message => serde_json::ser::to_string(&entry.message.as_ref().unwrap().for_device(device)).expect("couldn't serialize message"),
                     DBEntryType::Sendable => serde_json::ser::to_string(entry.sendable.as_ref().unwrap()).expect("couldn't serialize message"),
                 };
                 return (ContentType::JSON, serialized);
@@ -148,40 +227,50 @@ pub fn get_message(token: u32, chat: u32, message: u32, server_arc: &State<Arc<M
 }
 
 #[get("/db/chat-messages/<token>/<chat>?<number>&<after>")]
-pub fn get_chat_messages(token: u32, chat: u32, number: Option<usize>, after: Option<u128>, server_arc: &State<Arc<Mutex<Server>>>) -> (ContentType, String) {
-    let server = server_arc.lock().unwrap();
-    if server.tokens.lock().unwrap().contains_key(&token) {
-        let tokens = server.tokens.lock().unwrap();
-        let user_db = server.user_db.lock().unwrap();
-        let uid = tokens.get(&token).unwrap();
-        let udb = user_db.get(uid).unwrap();
+pub fn get_chat_messages(token: u32, chat: u32, number: Option<usize>, after: Option<u128>, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    if let Some(uid) = server.token_user(token) {
+        let device = session_device(server, token);
+        let shard = match server.user_db.get(&uid) {
+            Some(shard) => shard,
+            None => return (ContentType::JSON, "{\"server\":\"no chat found\"}".into()),
+        };
+        let udb = shard.lock();
         if udb.messages.contains_key(&chat) {
             let mut data = "[".to_string();
             let mut any_data = false;
+            // whether the listing reached the oldest entry still held
+            let mut complete = true;
             for (i, mid) in udb.messages.get(&chat).unwrap().timestamp_sorted.iter().enumerate() {
-                let entry = udb.messages.get(&chat).unwrap().map.get(&mid).unwrap();
+                let entry = udb.messages.get(&chat).unwrap().map.get(mid).unwrap();
                 if number.is_some() {
                     if i+1 >= number.unwrap() {
+                        complete = false;
                         break;
                     }
                 }
                 if after.is_some() {
                     if entry.get_timestamp() <= after.unwrap() {
+                        complete = false;
                         break;
                     }
                 }
                 let serialized = match entry.entry_type {
-                    DBEntryType::Message => Sendable::new(SendableType::Message, serde_json::ser::to_string(&entry.message.as_ref().unwrap()).expect("couldn't serialize message"), None).to_string(),
+                    DBEntryType::Message => Sendable::new(SendableType::Message, serde_json::ser::to_string(&entry.message.as_ref().unwrap().for_device(device)).expect("couldn't serialize message"), None).to_string(),
                     DBEntryType::Sendable => entry.sendable.as_ref().unwrap().to_string(),
                 };
                 data = format!("{}{},", data, serialized);
                 any_data = true;
             }
+            // older history was removed by retention, so tell the client there's nothing more to page through
+            if let Some(before) = udb.pruned.get(&chat).filter(|before| complete && after.map(|after| after < **before).unwrap_or(true)) {
+                data = format!("{}{},", data, pruned(chat, *before).to_string());
+                any_data = true;
+            }
             if any_data {
                 data.pop();
             }
             data += "]";
-            println!("{data}");
             return (ContentType::JSON, data);
         } else {
             return (ContentType::JSON, "{\"server\":\"no chat found\"}".into());
@@ -192,13 +281,14 @@ pub fn get_chat_messages(token: u32, chat: u32, number: Option<usize>, after: Op
 }
 
 #[get("/db/chats/<token>")]
-pub fn get_chats(token: u32, server_arc: &State<Arc<Mutex<Server>>>) -> (ContentType, String) {
-    let server = server_arc.lock().unwrap();
-    if server.tokens.lock().unwrap().contains_key(&token) {
-        let tokens = server.tokens.lock().unwrap();
-        let user_db = server.user_db.lock().unwrap();
-        let uid = tokens.get(&token).unwrap();
-        let udb = user_db.get(uid).unwrap();
+pub fn get_chats(token: u32, server_arc: &State<Arc<Server>>) -> (ContentType, String) {
+    let server: &Server = server_arc;
+    if let Some(uid) = server.token_user(token) {
+        let shard = match server.user_db.get(&uid) {
+            Some(shard) => shard,
+            None => return (ContentType::JSON, "[]".into()),
+        };
+        let udb = shard.lock();
         let mut data = "[".to_string();
         let mut any_data = false;
         for chatid in udb.messages.map.keys() {
@@ -209,7 +299,6 @@ pub fn get_chats(token: u32, server_arc: &State<Arc<Mutex<Server>>>) -> (Content
             data.pop();
         }
         data += "]";
-        println!("{data}");
         return (ContentType::JSON, data);
     } else {
         return (ContentType::JSON, "{\"server\":\"invalid token\"}".into());
@@ -0,0 +1,135 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::user::{UserIdentifier, UserProfile};
+
+#[derive(Deserialize, Clone)]
+pub struct ValidationConfig {
+    #[serde(default = "default_username_min_length")]
+    pub username_min_length: usize,
+    #[serde(default = "default_username_max_length")]
+    pub username_max_length: usize,
+    // allowed on top of ascii letters and digits, usernames still have to start with a letter or digit
+    #[serde(default = "default_username_extra_chars")]
+    pub username_extra_chars: String,
+    // compared case insensitively
+    #[serde(default = "default_reserved_usernames")]
+    pub reserved_usernames: Vec<String>,
+    #[serde(default = "default_display_name_max_length")]
+    pub display_name_max_length: usize,
+}
+
+fn default_username_min_length() -> usize {
+    3
+}
+
+fn default_username_max_length() -> usize {
+    32
+}
+
+fn default_username_extra_chars() -> String {
+    "_.-".to_string()
+}
+
+fn default_reserved_usernames() -> Vec<String> {
+    ["admin", "server", "system", "deleted", "root", "support"].iter().map(|name| name.to_string()).collect()
+}
+
+fn default_display_name_max_length() -> usize {
+    64
+}
+
+// field name to what's wrong with it, sorted so responses are stable
+#[derive(Default)]
+pub struct FieldErrors(BTreeMap<&'static str, String>);
+
+impl FieldErrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, field: &'static str, error: impl Into<String>) {
+        self.0.entry(field).or_insert_with(|| error.into());
+    }
+
+    pub fn check<T>(&mut self, field: &'static str, result: Result<T, String>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(error) => {
+                self.add(field, error);
+                None
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"server\":\"invalid\", \"errors\":{}}}",
+            serde_json::to_string(&self.0).expect("couldn't serialize field errors")
+        )
+    }
+}
+
+pub fn validate_username(username: &str, config: &ValidationConfig) -> Result<(), String> {
+    let length = username.chars().count();
+    if length < config.username_min_length || length > config.username_max_length {
+        return Err(format!(
+            "must be {} to {} characters",
+            config.username_min_length, config.username_max_length
+        ));
+    }
+    if !username.chars().next().map(|c| c.is_ascii_alphanumeric()).unwrap_or(false) {
+        return Err("must start with a letter or digit".to_string());
+    }
+    if let Some(c) = username.chars().find(|c| !c.is_ascii_alphanumeric() && !config.username_extra_chars.contains(*c)) {
+        return Err(format!("can't contain '{}'", c));
+    }
+    if config.reserved_usernames.iter().any(|reserved| reserved.eq_ignore_ascii_case(username)) {
+        return Err("is reserved".to_string());
+    }
+    Ok(())
+}
+
+// usernames are unique ignoring case so "Ann" can't impersonate "ann". `except` is the caller when renaming
+pub fn username_taken(username: &str, users: &HashMap<UserIdentifier, UserProfile>, except: Option<&UserIdentifier>) -> bool {
+    users
+        .keys()
+        .any(|uid| Some(uid) != except && uid.username.to_lowercase() == username.to_lowercase())
+}
+
+// NFC normalized and trimmed, so visually identical names compare and count the same
+pub fn normalize_display_name(name: &str, config: &ValidationConfig) -> Result<String, String> {
+    let name: String = name.nfc().collect::<String>().trim().to_string();
+    if name.is_empty() {
+        return Err("can't be empty".to_string());
+    }
+    if name.chars().count() > config.display_name_max_length {
+        return Err(format!("must be at most {} characters", config.display_name_max_length));
+    }
+    if name.chars().any(|c| c.is_control()) {
+        return Err("can't contain control characters".to_string());
+    }
+    Ok(name)
+}
+
+// accepts #rgb and #rrggbb and returns the lowercase #rrggbb form
+pub fn parse_color(color: &str) -> Result<String, String> {
+    let hex = match color.trim().strip_prefix('#') {
+        Some(hex) => hex,
+        None => return Err("must be a hex color like #1a2b3c".to_string()),
+    };
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("must be a hex color like #1a2b3c".to_string());
+    }
+    match hex.len() {
+        3 => Ok(format!("#{}", hex.chars().flat_map(|c| [c, c]).collect::<String>().to_lowercase())),
+        6 => Ok(format!("#{}", hex.to_lowercase())),
+        _ => Err("must be a hex color like #1a2b3c".to_string()),
+    }
+}
@@ -1,57 +1,95 @@
-use std::{sync::{Arc, Mutex, mpsc::channel}, convert::Infallible, time::Duration, net::SocketAddr};
+use std::{sync::{Arc, mpsc::{channel, TryRecvError}}, convert::Infallible, future::Future, time::Duration, net::SocketAddr};
 
 use futures::SinkExt;
 use rocket::tokio::time;
-use warp::{Filter, Reply, ws::{Ws, WebSocket, Message}, Rejection};
+use tracing::{debug, info, Instrument};
+use warp::{Filter, Reply, ws::{Ws, WebSocket, Message}, Rejection, http::StatusCode, reject::Reject};
 
-use crate::{Server, sendables::Sendable};
+use crate::{Server, logging::StreamSpan, rate_limit::{RateLimiter, RetryAfter}, sendables::Sendable, sessions::{add_event_sender, touch_session, ClientInfo}};
 
-pub async fn warp_start(server_arc: Arc<Mutex<Server>>) {
+#[derive(Debug)]
+struct RateLimited(RetryAfter);
+
+impl Reject for RateLimited {}
+
+// same per ip limits as the rocket side, websocket upgrades count against the "events-ws" route
+fn rate_limit(limiter: Arc<RateLimiter>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and_then(move |addr: Option<SocketAddr>| {
+            let limiter = limiter.clone();
+            async move {
+                match limiter.check_request(addr.map(|addr| addr.ip()), "events-ws") {
+                    Ok(()) => Ok(()),
+                    Err(retry_after) => Err(warp::reject::custom(RateLimited(retry_after))),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    match rejection.find::<RateLimited>() {
+        Some(RateLimited(retry_after)) => {
+            let body = format!("{{\"server\":\"rate limited\", \"retry_after\":{}}}", retry_after);
+            let reply = warp::reply::with_status(body, StatusCode::TOO_MANY_REQUESTS);
+            Ok(warp::reply::with_header(reply, "Retry-After", retry_after.to_string()))
+        }
+        None => Err(rejection),
+    }
+}
+
+// serves until `shutdown` resolves, then stops taking connections and returns once the open ones end
+pub async fn warp_start(server_arc: Arc<Server>, limiter: Arc<RateLimiter>, shutdown: impl Future<Output = ()> + Send + 'static) {
     let routes = warp::path!("events" / u32)
+        .and(rate_limit(limiter))
         // The `ws()` filter will prepare the Websocket handshake.
         .and(warp::ws())
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(warp::addr::remote())
         .and(with_server(server_arc.clone()))
-        .and_then(|token: u32, ws, server: Arc<Mutex<Server>>| async move {
-            return websocket(ws, token, server).await;
-        });
+        .and_then(|token: u32, ws, user_agent: Option<String>, addr: Option<SocketAddr>, server: Arc<Server>| async move {
+            let client = ClientInfo { user_agent, ip: addr.map(|addr| addr.ip().to_string()) };
+            return websocket(ws, token, client, server).await;
+        })
+        .recover(handle_rejection);
 
     let addr: SocketAddr = "0.0.0.0:8008".parse().unwrap();
 
-    println!("starting warp server");
-    warp::serve(routes).tls()
+    info!(%addr, "starting warp server");
+    let (addr, serving) = warp::serve(routes).tls()
     .cert_path("C:/Certbot/live/minecraft.themagicdoor.org/fullchain.pem")
     .key_path("C:/Certbot/live/minecraft.themagicdoor.org/privkey.pem")
-    .run(addr).await;
+    .bind_with_graceful_shutdown(addr, shutdown);
+    server_arc.health.set_warp_listening(true);
+    info!(%addr, "warp server listening");
+    serving.await;
+    server_arc.health.set_warp_listening(false);
+    info!("warp server stopped");
 }
 
-fn with_server(server: Arc<Mutex<Server>>) -> impl Filter<Extract = (Arc<Mutex<Server>>,), Error = Infallible> + Clone {
+fn with_server(server: Arc<Server>) -> impl Filter<Extract = (Arc<Server>,), Error = Infallible> + Clone {
     warp::any().map(move || server.clone())
 }
 
-pub async fn websocket(wb: Ws, token: u32, server_arc: Arc<Mutex<Server>>) -> Result<impl Reply, Rejection> {
-    println!("STARTING WEBSOCKET!");
-    let server = server_arc.lock().unwrap();
+pub async fn websocket(wb: Ws, token: u32, client: ClientInfo, server_arc: Arc<Server>) -> Result<impl Reply, Rejection> {
+    let server: &Server = &server_arc;
+    let stream_span = StreamSpan::open(server, token, "websocket");
     let (sender, receiver) = channel::<Sendable>();
-    let tokens = server.tokens.lock().unwrap().clone();
-    let uid = tokens.get(&token);
+    let uid = server.token_user(token);
     let mut invalid_token = false;
     if uid.is_none() {
         invalid_token = true;
     } else {
-        let mut event_stream_senders = server.event_stream_senders.lock().unwrap();
-        let senders_option = event_stream_senders.get(&uid.unwrap().clone());
-        let mut senders = Vec::new();
-        if senders_option.is_some() {
-            senders = senders_option.unwrap().clone();
-        }
-        senders.push(sender);
-        event_stream_senders.insert(uid.unwrap().clone(), senders);
+        add_event_sender(server, uid.as_ref().unwrap(), token, sender);
+        touch_session(server, token, &client);
     }
+    let span = stream_span.span.clone();
     return Ok(wb.on_upgrade(move |mut websocket: WebSocket| async move {
+        let _stream_span = stream_span;
         if invalid_token {
             match websocket.start_send_unpin(Message::text("{\"server_reponse\":\"invalid token\"}".to_string())) {
                 Ok(_) => {},
-                Err(e) => {println!("failed to send message{e}");}
+                Err(e) => {debug!("failed to send message: {e}");}
             };
         } else {
             let mut interval = time::interval(Duration::from_secs(1));
@@ -61,19 +99,28 @@ pub async fn websocket(wb: Ws, token: u32, server_arc: Arc<Mutex<Server>>) -> Re
                 if seconds >= 30 {
                     match websocket.start_send_unpin(Message::text(format!("{{\"server\":\"ping\"}}"))) {
                         Ok(_) => {},
-                        Err(e) => {println!("failed to send message{e}"); break;}
+                        Err(e) => {debug!("failed to send message: {e}"); break;}
                     };
                     seconds = 0;
                 }
-                let message_option = receiver.try_recv();
-                if message_option.is_ok() {
-                    match websocket.start_send_unpin(Message::text(format!("{}", message_option.unwrap().to_string()))) {
+                match receiver.try_recv() {
+                    Ok(message) => match websocket.start_send_unpin(Message::text(message.to_string())) {
                         Ok(_) => {},
-                        Err(e) => {println!("failed to send message{e}"); break;}
-                    };
+                        Err(e) => {debug!("failed to send message: {e}"); break;}
+                    },
+                    // the session was revoked or logged out, or the server is shutting down
+                    Err(TryRecvError::Disconnected) => {
+                        let _ = websocket.close().await;
+                        break;
+                    }
+                    Err(TryRecvError::Empty) if server_arc.health.shutting_down() => {
+                        let _ = websocket.close().await;
+                        break;
+                    }
+                    Err(TryRecvError::Empty) => {}
                 }
                 interval.tick().await;
             }
         }
-    }));
+    }.instrument(span)));
 }
\ No newline at end of file